@@ -0,0 +1,187 @@
+use clap::{builder::PossibleValuesParser, builder::TypedValueParser, Args, Parser, Subcommand};
+use eyre::{eyre, Result};
+
+use crate::config::{
+    CoryConfig, MAX_BPM, MAX_BUFFER_SIZE, MAX_SAMPLE_RATE, MAX_SUBDIVISION, MAX_TOTAL_BEATS,
+    MAX_VOLUME, MIN_BPM, MIN_BUFFER_SIZE, MIN_SAMPLE_RATE, MIN_SUBDIVISION, MIN_TOTAL_BEATS,
+    MIN_VOLUME,
+};
+
+/// A metronome in the terminal
+///
+/// The options override the config for the session only, they are not saved.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Tempo in beats per minute
+    #[arg(long, global = true, value_parser = |x: &str| parse_in_range(x, MIN_BPM, MAX_BPM))]
+    pub bpm: Option<f64>,
+
+    /// Volume from 0 to 1
+    #[arg(
+        long,
+        global = true,
+        value_parser = |x: &str| parse_in_range(x, MIN_VOLUME, MAX_VOLUME),
+    )]
+    pub volume: Option<f64>,
+
+    /// Number of beats per bar
+    #[arg(
+        long,
+        global = true,
+        value_parser = clap::value_parser!(u32).range(MIN_TOTAL_BEATS as i64..=MAX_TOTAL_BEATS as i64),
+    )]
+    pub beats: Option<u32>,
+
+    /// Number of pulses each beat is divided into
+    #[arg(
+        long,
+        global = true,
+        value_parser = clap::value_parser!(u32).range(MIN_SUBDIVISION as i64..=MAX_SUBDIVISION as i64),
+    )]
+    pub subdivision: Option<u32>,
+
+    /// WAV, FLAC or Ogg Vorbis file played for the clicks instead of the
+    /// built-in one, a kit takes precedence over it
+    #[arg(long, value_name = "PATH", global = true)]
+    pub sample: Option<String>,
+
+    /// WAV, FLAC or Ogg Vorbis file played on accented beats, a kit takes
+    /// precedence over it
+    #[arg(long, value_name = "PATH", global = true)]
+    pub accent_sample: Option<String>,
+
+    /// Sound kit to load from the kits directory
+    #[arg(long, value_name = "NAME", global = true)]
+    pub kit: Option<String>,
+
+    /// Audio host to play on by name or index, see --list-devices
+    #[arg(long, value_name = "NAME", global = true)]
+    pub host: Option<String>,
+
+    /// Output device to play on by name or index, see --list-devices
+    #[arg(long, value_name = "NAME", global = true)]
+    pub device: Option<String>,
+
+    /// Sample rate in Hz, the one of the device if not given (48000 when
+    /// rendering)
+    #[arg(
+        long,
+        global = true,
+        value_parser = clap::value_parser!(u32).range(MIN_SAMPLE_RATE as i64..=MAX_SAMPLE_RATE as i64),
+    )]
+    pub sample_rate: Option<u32>,
+
+    /// Buffer size in frames, the one of the device if not given
+    #[arg(
+        long,
+        global = true,
+        value_parser = clap::value_parser!(u32).range(MIN_BUFFER_SIZE as i64..=MAX_BUFFER_SIZE as i64),
+    )]
+    pub buffer_size: Option<u32>,
+
+    /// Config file to use instead of the one in the config directory
+    #[arg(long, value_name = "PATH", global = true)]
+    pub config: Option<String>,
+
+    /// Do not write the config when quitting
+    #[arg(long, global = true)]
+    pub no_save: bool,
+
+    /// List the sound kits in the kits directory and exit
+    #[arg(long)]
+    pub list_kits: bool,
+
+    /// List the audio hosts and their output devices and exit
+    #[arg(long)]
+    pub list_devices: bool,
+
+    /// Print a line for every beat instead of showing the TUI, until
+    /// interrupted (SIGUSR1 starts or stops, SIGUSR2 restarts)
+    #[arg(long)]
+    pub headless: bool,
+}
+
+impl Cli {
+    /// Overrides the config with the given options.
+    pub fn apply(&self, config: &mut CoryConfig) -> Result<()> {
+        if let Some(bpm) = self.bpm {
+            if !(config.min_bpm..=config.max_bpm).contains(&bpm) {
+                return Err(eyre!(
+                    "BPM {} is outside of the configured range {}-{}",
+                    bpm,
+                    config.min_bpm,
+                    config.max_bpm
+                ));
+            }
+            config.bpm = bpm;
+        }
+        if let Some(volume) = self.volume {
+            config.volume = volume;
+        }
+        if let Some(beats) = self.beats {
+            config.total_beats = beats;
+        }
+        if let Some(subdivision) = self.subdivision {
+            config.subdivision = subdivision;
+        }
+        if self.accent_sample.is_some() {
+            config.accent_sample = self.accent_sample.clone();
+        }
+        if self.host.is_some() {
+            config.host = self.host.clone();
+        }
+        if self.device.is_some() {
+            config.device = self.device.clone();
+        }
+        if self.sample_rate.is_some() {
+            config.sample_rate = self.sample_rate;
+        }
+        if self.buffer_size.is_some() {
+            config.buffer_size = self.buffer_size;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Render a click track to a WAV file instead of playing it
+    ///
+    /// The trainer and the gap mode are ignored, every bar is played at the
+    /// same tempo.
+    Render(RenderArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct RenderArgs {
+    /// The WAV file to write
+    #[arg(value_name = "PATH")]
+    pub output: String,
+
+    /// Number of bars, not counting the count-in
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..))]
+    pub bars: u32,
+
+    /// 16 or 24 bit integer, or 32 bit float samples
+    #[arg(
+        long,
+        default_value_t = 16,
+        value_parser = PossibleValuesParser::new(["16", "24", "32"]).map(|x| x.parse::<u16>().unwrap()),
+    )]
+    pub bit_depth: u16,
+}
+
+fn parse_in_range(value: &str, min: f64, max: f64) -> Result<f64, String> {
+    let value: f64 = value
+        .parse()
+        .map_err(|_| format!("'{}' is not a number", value))?;
+    if (min..=max).contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!("{} is not in {}..={}", value, min, max))
+    }
+}
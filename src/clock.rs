@@ -0,0 +1,150 @@
+// tempos are counted in thousandths of a BPM
+const TEMPO_SCALE: u64 = 1000;
+
+/// A position within the bar, counted exactly in fractions of a beat.
+///
+/// A beat is `60 * TEMPO_SCALE * sample_rate` ticks and every frame adds the
+/// tempo in thousandths of a BPM, so the position is an integer sample
+/// counter scaled by the tempo and no error builds up however long it runs.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    sample_rate: u32,
+    ticks: u64,
+}
+
+impl Clock {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate: sample_rate.max(1),
+            ticks: 0,
+        }
+    }
+
+    pub fn beat_ticks(&self) -> u64 {
+        60 * TEMPO_SCALE * self.sample_rate as u64
+    }
+
+    /// Keeps the position when the stream runs at another rate.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let sample_rate = sample_rate.max(1);
+        if sample_rate != self.sample_rate {
+            self.ticks =
+                (self.ticks as u128 * sample_rate as u128 / self.sample_rate as u128) as u64;
+            self.sample_rate = sample_rate;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.ticks = 0;
+    }
+
+    /// Moves the position by a number of frames at the given tempo.
+    pub fn advance(&mut self, frames: u64, bpm: f64) {
+        self.ticks += frames * tempo_ticks(bpm);
+    }
+
+    /// Whether the position has reached `num / den` beats.
+    pub fn reached(&self, num: u64, den: u64) -> bool {
+        self.ticks as u128 * den as u128 >= num as u128 * self.beat_ticks() as u128
+    }
+
+    /// Number of the divisions of `beats` beats into `n` equal parts that
+    /// have started.
+    pub fn divisions(&self, n: u64, beats: u64) -> u64 {
+        (self.ticks as u128 * n as u128 / (beats.max(1) as u128 * self.beat_ticks() as u128)) as u64
+    }
+
+    /// Drops the whole beats, only keeping the phase within the beat.
+    pub fn wrap(&mut self) {
+        self.ticks %= self.beat_ticks();
+    }
+
+    /// Number of frames before the position reaches `num / den` beats at the
+    /// given tempo, 0 if it has already.
+    pub fn frames_until(&self, num: u64, den: u64, bpm: f64) -> u64 {
+        let target = (num as u128 * self.beat_ticks() as u128).div_ceil(den.max(1) as u128);
+        let remaining = target.saturating_sub(self.ticks as u128);
+        remaining.div_ceil(tempo_ticks(bpm) as u128) as u64
+    }
+}
+
+/// Ticks added by every frame at the given tempo, never 0.
+fn tempo_ticks(bpm: f64) -> u64 {
+    ((bpm * TEMPO_SCALE as f64).round() as u64).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOURS: u64 = 3;
+
+    /// Runs 4/4 bars for `HOURS` hours, checking that every beat starts on
+    /// the first frame at or after its exact time.
+    fn assert_no_drift(sample_rate: u32, bpm: f64) {
+        let total_beats = 4;
+        // the exact tempo as a fraction of a BPM
+        let bpm_num = (bpm * TEMPO_SCALE as f64).round() as u128;
+        let frames_per_beat_num = 60 * TEMPO_SCALE as u128 * sample_rate as u128;
+        let n_beats = (HOURS as u128 * 3600 * bpm_num / (60 * TEMPO_SCALE as u128)) as u64;
+
+        let mut clock = Clock::new(sample_rate);
+        let mut frame = 0;
+        let mut beat_in_bar = 0;
+        for beat in 1..=n_beats {
+            // frame at or after the exact time of the beat
+            let expected = (beat as u128 * frames_per_beat_num).div_ceil(bpm_num) as u64;
+            let exact = beat as f64 * 60.0 * sample_rate as f64 / bpm;
+            assert!((expected as f64 - exact).abs() < 1.0);
+
+            beat_in_bar += 1;
+            clock.advance(expected - 1 - frame, bpm);
+            assert!(!clock.reached(beat_in_bar, 1), "beat {} is early", beat);
+            clock.advance(1, bpm);
+            assert!(clock.reached(beat_in_bar, 1), "beat {} is late", beat);
+            frame = expected;
+
+            if beat_in_bar == total_beats {
+                clock.wrap();
+                beat_in_bar = 0;
+            }
+        }
+    }
+
+    #[test]
+    fn no_drift_at_44100() {
+        assert_no_drift(44100, 120.0);
+        assert_no_drift(44100, 97.3);
+    }
+
+    #[test]
+    fn no_drift_at_48000() {
+        assert_no_drift(48000, 120.0);
+        assert_no_drift(48000, 133.3);
+    }
+
+    #[test]
+    fn no_drift_at_96000() {
+        assert_no_drift(96000, 60.0);
+        assert_no_drift(96000, 187.7);
+    }
+
+    #[test]
+    fn divisions_are_exact() {
+        let mut clock = Clock::new(48000);
+        // 3 pulses over 2 beats at 60 BPM, the second one at 2/3 of a second
+        clock.advance(31999, 60.0);
+        assert_eq!(clock.divisions(3, 2), 0);
+        clock.advance(1, 60.0);
+        assert_eq!(clock.divisions(3, 2), 1);
+    }
+
+    #[test]
+    fn sample_rate_change_keeps_position() {
+        let mut clock = Clock::new(48000);
+        clock.advance(24000, 120.0);
+        clock.set_sample_rate(96000);
+        assert!(clock.reached(1, 1));
+        assert!(!clock.reached(1000001, 1000000));
+    }
+}
@@ -0,0 +1,86 @@
+use eyre::{eyre, Result};
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::pattern::Pattern;
+use crate::sampler::Sounds;
+
+// commands the UI can send before the audio thread catches up
+const COMMAND_CAPACITY: usize = 64;
+
+/// A change applied by the audio thread at the start of a block.
+///
+/// Once applied, a command holds what it replaced and is sent back so that
+/// the memory is freed outside of the audio thread.
+#[derive(Debug)]
+pub enum SamplerCommand {
+    /// Replaces every sound, the samples must be prepared for the stream
+    Sounds(Box<Sounds>),
+    /// Replaces the pattern and the number of beats together
+    Pattern { pattern: Pattern, total_beats: u32 },
+    /// Changes the BPM
+    Tempo(f64),
+    /// Rewinds to the start of bar one
+    Restart,
+}
+
+/// The UI end of the command queue
+#[derive(Debug)]
+pub struct CommandSender {
+    commands: Producer<SamplerCommand>,
+    spent: Consumer<SamplerCommand>,
+}
+
+impl CommandSender {
+    pub fn send(&mut self, command: SamplerCommand) -> Result<()> {
+        self.collect();
+        self.commands
+            .push(command)
+            .map_err(|_| eyre!("The audio thread is not keeping up with the changes"))
+    }
+
+    /// Frees the commands the audio thread is done with.
+    pub fn collect(&mut self) {
+        while let Ok(command) = self.spent.pop() {
+            drop(command);
+        }
+    }
+}
+
+/// The audio thread end of the command queue
+#[derive(Debug)]
+pub struct CommandReceiver {
+    commands: Consumer<SamplerCommand>,
+    spent: Producer<SamplerCommand>,
+}
+
+impl CommandReceiver {
+    /// Takes the next command, only if there is room to send it back.
+    pub fn pop(&mut self) -> Option<SamplerCommand> {
+        if self.spent.is_full() {
+            return None;
+        }
+        self.commands.pop().ok()
+    }
+
+    /// Sends an applied command back to the UI.
+    pub fn recycle(&mut self, command: SamplerCommand) {
+        // `pop` has made sure there is room, the command is never dropped here
+        self.spent.push(command).ok();
+    }
+}
+
+/// Creates a command queue, its audio thread end never allocates, frees or locks.
+pub fn command_queue() -> (CommandSender, CommandReceiver) {
+    let (command_producer, command_consumer) = RingBuffer::new(COMMAND_CAPACITY);
+    let (spent_producer, spent_consumer) = RingBuffer::new(COMMAND_CAPACITY);
+    (
+        CommandSender {
+            commands: command_producer,
+            spent: spent_consumer,
+        },
+        CommandReceiver {
+            commands: command_consumer,
+            spent: spent_producer,
+        },
+    )
+}
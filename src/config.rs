@@ -1,32 +1,173 @@
 use std::{
     fs::{self, File},
     io::{BufReader, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use directories::ProjectDirs;
 use eyre::{eyre, Result};
 use serde::{Deserialize, Serialize};
 
-pub const MIN_BPM: f64 = 20.0;
-pub const MAX_BPM: f64 = 200.0;
+use crate::pattern::{default_pattern, BeatKind};
+use crate::sampler::{GapMode, TempoChange};
+use crate::synth::SynthKit;
+use crate::utils::round_by_precision;
+
+// the widest BPM range that can be configured
+pub const MIN_BPM: f64 = 10.0;
+pub const MAX_BPM: f64 = 600.0;
+// decimals kept in a BPM
+pub const BPM_PRECISION: u32 = 1;
+// the BPM steps of the arrow keys, from coarse to fine
+pub const BPM_STEPS: [f64; 3] = [5.0, 1.0, 0.1];
 pub const MAX_TOTAL_BEATS: u32 = 12;
 pub const MIN_TOTAL_BEATS: u32 = 2;
 pub const MAX_VOLUME: f64 = 1.0; // a hack for float precision issue
 pub const MIN_VOLUME: f64 = 0.0;
+pub const MAX_PAN: f64 = 1.0;
+pub const MIN_PAN: f64 = -1.0;
+pub const MAX_ACCENT_GAIN: f64 = 2.0;
+pub const MIN_ACCENT_GAIN: f64 = 0.0;
+pub const MAX_ACCENT_PITCH: f64 = 2.0;
+pub const MIN_ACCENT_PITCH: f64 = 0.5;
+pub const MAX_SUBDIVISION: u32 = 6;
+pub const MIN_SUBDIVISION: u32 = 1;
+pub const MAX_SUBDIVISION_GAIN: f64 = 1.0;
+pub const MIN_SUBDIVISION_GAIN: f64 = 0.0;
+pub const MAX_SWING: f64 = 0.75;
+pub const MIN_SWING: f64 = 0.5;
+pub const MAX_GHOST_GAIN: f64 = 1.0;
+pub const MIN_GHOST_GAIN: f64 = 0.0;
+pub const MAX_POLY_BEATS: u32 = 12;
+pub const MIN_POLY_BEATS: u32 = 2;
+pub const MAX_VOICE_GAIN: f64 = 1.0;
+pub const MIN_VOICE_GAIN: f64 = 0.0;
+pub const MAX_TRAINER_BARS: u32 = 64;
+pub const MIN_TRAINER_BARS: u32 = 1;
+pub const MAX_TRAINER_STEP: f64 = 20.0;
+pub const MIN_TRAINER_STEP: f64 = 0.0;
+pub const MAX_GAP_BARS: u32 = 16;
+pub const MIN_GAP_BARS: u32 = 1;
+pub const MAX_MUTE_PROBABILITY: f64 = 1.0;
+pub const MIN_MUTE_PROBABILITY: f64 = 0.0;
+pub const MAX_COUNT_IN_BARS: u32 = 2;
+pub const MIN_COUNT_IN_BARS: u32 = 0;
+pub const MAX_SAMPLE_RATE: u32 = 192000;
+pub const MIN_SAMPLE_RATE: u32 = 8000;
+// buffer sizes are in frames
+pub const MAX_BUFFER_SIZE: u32 = 8192;
+pub const MIN_BUFFER_SIZE: u32 = 16;
 // pub const PRECISION: u32 = 2;
 
-#[derive(Serialize, Deserialize)]
+/// Where the click sounds come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SoundSource {
+    /// The sample file of `CoryConfig::sample`, or the built-in click
+    Sample,
+    /// The synthesized clicks of `CoryConfig::synth`
+    Synth,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct CoryConfig {
     pub bpm: f64,
+    /// Whether the click starts as soon as cory is launched
+    pub play_on_launch: bool,
+    /// The lowest BPM that can be set
+    pub min_bpm: f64,
+    /// The highest BPM that can be set
+    pub max_bpm: f64,
+    /// BPM added or removed by the arrow keys, one of `BPM_STEPS`
+    pub bpm_step: f64,
+    /// When a new BPM takes effect
+    pub tempo_change: TempoChange,
     pub volume: f64,
+    pub pan: f64,
+    pub total_beats: u32,
+    pub accent_gain: f64,
+    pub accent_pitch: f64,
+    pub subdivision: u32,
+    pub subdivision_gain: f64,
+    pub swing: f64,
+    pub ghost_gain: f64,
+    pub pattern: Vec<BeatKind>,
+    pub polyrhythm: bool,
+    pub poly_beats: u32,
+    pub main_gain: f64,
+    pub poly_gain: f64,
+    pub trainer: bool,
+    pub trainer_step: f64,
+    pub trainer_bars: u32,
+    pub trainer_target: f64,
+    pub gap_mode: GapMode,
+    pub gap_play_bars: u32,
+    pub gap_mute_bars: u32,
+    pub mute_probability: f64,
+    /// Number of bars counted in when the click starts, 0 for none
+    pub count_in_bars: u32,
+    pub sound: SoundSource,
+    /// WAV, FLAC or Ogg Vorbis file played for the clicks instead of the built-in one
+    pub sample: Option<String>,
+    /// WAV, FLAC or Ogg Vorbis file played on accented beats instead of the
+    /// accent of `sample` or `sound`
+    pub accent_sample: Option<String>,
+    /// Name of the sound kit in the kits directory, it takes precedence
+    /// over `sample` and `sound`
+    pub kit: Option<String>,
+    pub synth: SynthKit,
+    /// Audio host by name or index, the default one if not set
+    pub host: Option<String>,
+    /// Output device by name or index, the default one if not set
+    pub device: Option<String>,
+    /// Sample rate in Hz, the one of the device if not set
+    pub sample_rate: Option<u32>,
+    /// Buffer size in frames, the one of the device if not set
+    pub buffer_size: Option<u32>,
 }
 
 impl Default for CoryConfig {
     fn default() -> Self {
         Self {
             bpm: 120.0,
+            play_on_launch: true,
+            min_bpm: 20.0,
+            max_bpm: 200.0,
+            bpm_step: 1.0,
+            tempo_change: TempoChange::Beat,
             volume: 1.0,
+            pan: 0.0,
+            total_beats: 4,
+            accent_gain: 1.0,
+            accent_pitch: 1.5,
+            subdivision: 1,
+            subdivision_gain: 0.5,
+            swing: 0.5,
+            ghost_gain: 0.3,
+            pattern: default_pattern(),
+            polyrhythm: false,
+            poly_beats: 3,
+            main_gain: 1.0,
+            poly_gain: 1.0,
+            trainer: false,
+            trainer_step: 5.0,
+            trainer_bars: 4,
+            trainer_target: 160.0,
+            gap_mode: GapMode::Off,
+            gap_play_bars: 2,
+            gap_mute_bars: 2,
+            mute_probability: 0.3,
+            count_in_bars: 0,
+            sound: SoundSource::Sample,
+            sample: None,
+            accent_sample: None,
+            kit: None,
+            synth: SynthKit::default(),
+            host: None,
+            device: None,
+            sample_rate: None,
+            buffer_size: None,
         }
     }
 }
@@ -34,27 +175,26 @@ impl Default for CoryConfig {
 impl CoryConfig {
     #[allow(dead_code)]
     pub fn new(bpm: f64, volume: f64) -> Self {
-        Self { bpm, volume }
+        Self {
+            bpm,
+            volume,
+            ..Default::default()
+        }
     }
 
-    pub fn load() -> Result<Self> {
-        let config_path = get_config_path()?;
+    pub fn load(config_path: &Path) -> Result<Self> {
         match File::open(config_path) {
             Ok(file) => {
                 let reader = BufReader::new(file);
-                let config: CoryConfig = match serde_json::from_reader(reader) {
-                    Ok(x) => x,
-                    Err(_) => Self::default(),
-                };
+                let config: CoryConfig = serde_json::from_reader(reader).unwrap_or_default();
                 Ok(config.to_rounded())
             }
             Err(_) => Ok(Self::default()),
         }
     }
 
-    pub fn write(&self) -> Result<()> {
+    pub fn write(&self, config_path: &Path) -> Result<()> {
         let json_str = serde_json::to_string(&self.to_rounded())?;
-        let config_path = get_config_path()?;
         if let Some(parent_dir) = config_path.parent() {
             fs::create_dir_all(parent_dir)?;
         }
@@ -64,24 +204,87 @@ impl CoryConfig {
     }
 
     fn to_rounded(&self) -> Self {
+        let min_bpm = round_by_precision(self.min_bpm.clamp(MIN_BPM, MAX_BPM), BPM_PRECISION);
+        let max_bpm = round_by_precision(self.max_bpm.clamp(min_bpm, MAX_BPM), BPM_PRECISION);
+        let bpm_step = if BPM_STEPS.contains(&self.bpm_step) {
+            self.bpm_step
+        } else {
+            1.0
+        };
         Self {
-            bpm: self.bpm.clamp(MIN_BPM, MAX_BPM),
+            bpm: round_by_precision(self.bpm.clamp(min_bpm, max_bpm), BPM_PRECISION),
+            play_on_launch: self.play_on_launch,
+            min_bpm,
+            max_bpm,
+            bpm_step,
+            tempo_change: self.tempo_change,
             volume: self.volume.clamp(MIN_VOLUME, MAX_VOLUME),
+            pan: self.pan.clamp(MIN_PAN, MAX_PAN),
+            total_beats: self.total_beats.clamp(MIN_TOTAL_BEATS, MAX_TOTAL_BEATS),
+            accent_gain: self.accent_gain.clamp(MIN_ACCENT_GAIN, MAX_ACCENT_GAIN),
+            accent_pitch: self.accent_pitch.clamp(MIN_ACCENT_PITCH, MAX_ACCENT_PITCH),
+            subdivision: self.subdivision.clamp(MIN_SUBDIVISION, MAX_SUBDIVISION),
+            subdivision_gain: self
+                .subdivision_gain
+                .clamp(MIN_SUBDIVISION_GAIN, MAX_SUBDIVISION_GAIN),
+            swing: self.swing.clamp(MIN_SWING, MAX_SWING),
+            ghost_gain: self.ghost_gain.clamp(MIN_GHOST_GAIN, MAX_GHOST_GAIN),
+            pattern: self.pattern.clone(),
+            polyrhythm: self.polyrhythm,
+            poly_beats: self.poly_beats.clamp(MIN_POLY_BEATS, MAX_POLY_BEATS),
+            main_gain: self.main_gain.clamp(MIN_VOICE_GAIN, MAX_VOICE_GAIN),
+            poly_gain: self.poly_gain.clamp(MIN_VOICE_GAIN, MAX_VOICE_GAIN),
+            trainer: self.trainer,
+            trainer_step: self.trainer_step.clamp(MIN_TRAINER_STEP, MAX_TRAINER_STEP),
+            trainer_bars: self.trainer_bars.clamp(MIN_TRAINER_BARS, MAX_TRAINER_BARS),
+            trainer_target: self.trainer_target.clamp(min_bpm, max_bpm),
+            gap_mode: self.gap_mode,
+            gap_play_bars: self.gap_play_bars.clamp(MIN_GAP_BARS, MAX_GAP_BARS),
+            gap_mute_bars: self.gap_mute_bars.clamp(MIN_GAP_BARS, MAX_GAP_BARS),
+            mute_probability: self
+                .mute_probability
+                .clamp(MIN_MUTE_PROBABILITY, MAX_MUTE_PROBABILITY),
+            count_in_bars: self
+                .count_in_bars
+                .clamp(MIN_COUNT_IN_BARS, MAX_COUNT_IN_BARS),
+            sound: self.sound,
+            sample: self.sample.clone(),
+            accent_sample: self.accent_sample.clone(),
+            kit: self.kit.clone(),
+            synth: self.synth,
+            host: self.host.clone(),
+            device: self.device.clone(),
+            sample_rate: self
+                .sample_rate
+                .map(|x| x.clamp(MIN_SAMPLE_RATE, MAX_SAMPLE_RATE)),
+            buffer_size: self
+                .buffer_size
+                .map(|x| x.clamp(MIN_BUFFER_SIZE, MAX_BUFFER_SIZE)),
         }
     }
 }
 
-fn get_config_path() -> Result<PathBuf> {
-    let mut directory = if let Ok(s) = std::env::var("CORY_CONFIG") {
-        PathBuf::from(s)
+fn get_config_dir() -> Result<PathBuf> {
+    if let Ok(s) = std::env::var("CORY_CONFIG") {
+        Ok(PathBuf::from(s))
     } else if let Some(proj_dirs) = ProjectDirs::from("com", "yz", "cory") {
-        proj_dirs.config_local_dir().to_path_buf()
+        Ok(proj_dirs.config_local_dir().to_path_buf())
     } else {
-        return Err(eyre!(
+        Err(eyre!(
             "Unable to find config directory for ratatui-template"
-        ));
-    };
+        ))
+    }
+}
 
+pub fn get_config_path() -> Result<PathBuf> {
+    let mut directory = get_config_dir()?;
     directory.push("config.json");
     Ok(directory)
 }
+
+/// The directory holding one sub-directory per sound kit
+pub fn get_kits_dir() -> Result<PathBuf> {
+    let mut directory = get_config_dir()?;
+    directory.push("kits");
+    Ok(directory)
+}
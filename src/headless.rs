@@ -0,0 +1,86 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use eyre::Result;
+use signal_hook::consts::TERM_SIGNALS;
+use signal_hook::flag;
+
+use crate::command::{CommandSender, SamplerCommand};
+use crate::sampler::{SamplerEvent, SamplerParam};
+
+// how often the signals are checked when no beat comes
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Prints a line to stdout for every beat until a termination signal.
+///
+/// On unix, `SIGUSR1` starts or stops the click and `SIGUSR2` restarts it
+/// from bar one.
+pub fn run(
+    param: Arc<SamplerParam>,
+    mut commands: CommandSender,
+    events: &Receiver<SamplerEvent>,
+) -> Result<()> {
+    let quit = Arc::new(AtomicBool::new(false));
+    for signal in TERM_SIGNALS {
+        flag::register(*signal, quit.clone())?;
+    }
+    let toggle = Arc::new(AtomicBool::new(false));
+    let restart = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    {
+        use signal_hook::consts::{SIGUSR1, SIGUSR2};
+        flag::register(SIGUSR1, toggle.clone())?;
+        flag::register(SIGUSR2, restart.clone())?;
+    }
+
+    let mut stdout = io::stdout();
+    while !quit.load(Ordering::Relaxed) {
+        if toggle.swap(false, Ordering::Relaxed) {
+            let playing = param.playing.load(Ordering::Relaxed);
+            param.playing.store(!playing, Ordering::Relaxed);
+        }
+        if restart.swap(false, Ordering::Relaxed) {
+            commands.send(SamplerCommand::Restart)?;
+        }
+        commands.collect();
+
+        let event = match events.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if let SamplerEvent::Beat {
+            beat,
+            bar,
+            muted,
+            count_in,
+        } = event
+        {
+            let total_beats = param.total_beats.load(Ordering::Relaxed);
+            let bpm = param.bpm.load(Ordering::Relaxed);
+            // the bars and beats count from 1 like in the TUI
+            let bar = if count_in {
+                "count-in".to_string()
+            } else {
+                format!("bar {}", bar + 1)
+            };
+            let muted = if muted { " muted" } else { "" };
+            let line = format!(
+                "{} beat {}/{} {} BPM{}",
+                bar,
+                beat + 1,
+                total_beats,
+                bpm,
+                muted
+            );
+            // a closed stdout, e.g. the end of a pipe, ends the session
+            if writeln!(stdout, "{}", line).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
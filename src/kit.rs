@@ -0,0 +1,92 @@
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::Path,
+};
+
+use eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+
+use crate::config::get_kits_dir;
+use crate::sample::Sample;
+
+/// The manifest every kit directory must contain
+pub const KIT_MANIFEST: &str = "kit.json";
+
+/// The content of `kit.json`, sample files are relative to the kit directory
+#[derive(Deserialize)]
+struct KitManifest {
+    normal: KitEntry,
+    accent: Option<KitEntry>,
+    subdivision: Option<KitEntry>,
+    count_in: Option<KitEntry>,
+}
+
+#[derive(Deserialize)]
+struct KitEntry {
+    file: String,
+    #[serde(default = "default_gain")]
+    gain: f64,
+}
+
+fn default_gain() -> f64 {
+    1.0
+}
+
+/// A set of samples, loaded with their gain applied
+#[derive(Debug)]
+pub struct Kit {
+    pub normal: Sample,
+    pub accent: Option<Sample>,
+    pub subdivision: Option<Sample>,
+    pub count_in: Option<Sample>,
+}
+
+impl Kit {
+    /// Loads a kit from the kits directory by the name of its directory.
+    pub fn load_by_name(name: &str) -> Result<Self> {
+        Self::load(get_kits_dir()?.join(name))
+    }
+
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        let manifest_path = dir.join(KIT_MANIFEST);
+        let file = File::open(&manifest_path).wrap_err_with(|| {
+            format!("Unable to open kit manifest '{}'", manifest_path.display())
+        })?;
+        let manifest: KitManifest = serde_json::from_reader(BufReader::new(file))
+            .wrap_err_with(|| format!("Invalid kit manifest '{}'", manifest_path.display()))?;
+
+        let load_entry = |entry: &KitEntry| -> Result<Sample> {
+            if !entry.gain.is_finite() || entry.gain < 0.0 {
+                return Err(eyre!("Invalid gain {} for '{}'", entry.gain, entry.file));
+            }
+            Ok(Sample::from_path(dir.join(&entry.file))?.scaled(entry.gain))
+        };
+
+        Ok(Self {
+            normal: load_entry(&manifest.normal)?,
+            accent: manifest.accent.as_ref().map(load_entry).transpose()?,
+            subdivision: manifest.subdivision.as_ref().map(load_entry).transpose()?,
+            count_in: manifest.count_in.as_ref().map(load_entry).transpose()?,
+        })
+    }
+}
+
+/// Names of the kit directories in the kits directory, sorted.
+///
+/// A missing kits directory means there is no kit.
+pub fn list_kits() -> Result<Vec<String>> {
+    let kits_dir = get_kits_dir()?;
+    let entries = match fs::read_dir(&kits_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join(KIT_MANIFEST).is_file())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    Ok(names)
+}
@@ -1,62 +1,124 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    mpsc::channel,
-    Arc,
-};
+use std::path::PathBuf;
+use std::sync::{atomic::Ordering, mpsc::channel, Arc};
 
-use cpal::traits::{HostTrait, StreamTrait};
+use clap::Parser;
 use eyre::Result;
 use ratatui::{backend::CrosstermBackend, Terminal};
 
-use crate::config::CoryConfig;
-use crate::playback::init_stream;
-use crate::sampler::{Sampler, SamplerParam};
+use crate::cli::{Cli, Command};
+use crate::command::SamplerCommand;
+use crate::config::{get_config_path, CoryConfig, SoundSource};
+use crate::kit::{list_kits, Kit};
+use crate::playback::{list_output_devices, open_output};
+use crate::render::render;
+use crate::sample::Sample;
+use crate::sampler::{Sampler, SamplerParam, Sounds};
 use crate::tui::{App, Tui, UIEventCapturer};
-use crate::utils::AtomicF64;
 
+mod cli;
+mod clock;
+mod command;
 mod config;
+mod headless;
+mod kit;
+mod pattern;
 mod playback;
+mod render;
+mod sample;
 mod sampler;
+mod synth;
 mod tui;
 mod utils;
 
 fn main() -> Result<()> {
-    // Load config
-    let mut config = CoryConfig::load()?;
+    let cli = Cli::parse();
+    if cli.list_kits {
+        for name in list_kits()? {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+    if cli.list_devices {
+        for (idx, (host, devices)) in list_output_devices().iter().enumerate() {
+            println!("{}: {}", idx, host);
+            for (idx, (name, is_default)) in devices.iter().enumerate() {
+                let mark = if *is_default { " (default)" } else { "" };
+                println!("  {}: {}{}", idx, name, mark);
+            }
+        }
+        return Ok(());
+    }
+
+    // Load config, the command line overrides it for the session
+    let config_path = match cli.config {
+        Some(ref path) => PathBuf::from(path),
+        None => get_config_path()?,
+    };
+    let mut config = CoryConfig::load(&config_path)?;
+    let mut session = config.clone();
+    cli.apply(&mut session)?;
+
+    // Load sounds
+    let mut kit = cli.kit.clone().or(session.kit.clone());
+    let sample_path = match session.sound {
+        SoundSource::Sample => cli.sample.clone().or(session.sample.clone()),
+        SoundSource::Synth => cli.sample.clone(),
+    };
+    let (sounds, warning) = load_sounds(sample_path.as_deref(), kit.as_deref(), &session);
+
+    if let Some(Command::Render(ref args)) = cli.command {
+        if let Some(warning) = warning {
+            eprintln!("{}", warning);
+        }
+        return render(args, &session, sounds);
+    }
 
     // Initialize channel
     let (sampler_event_sender, sampler_event_receiver) = channel();
 
     // Initialize sampler
-    let param = Arc::new(SamplerParam {
-        bpm: AtomicF64::new(config.bpm),
-        playing: AtomicBool::new(true),
-        volume: AtomicF64::new(config.volume),
-    });
-    let sampler = Sampler::new(param.clone(), Some(sampler_event_sender.clone()))?;
+    let param = Arc::new(SamplerParam::from_config(&session));
+    let sender = sampler_event_sender.clone();
+    let mut sampler = Sampler::new(sounds, param.clone(), Some(sender));
+    let commands = sampler.command_queue();
 
     // Initialize audio device
-    let host = cpal::default_host();
-    let device = host.default_output_device().unwrap();
-    let stream = init_stream(&device, sampler);
+    let (output, sample_rate, output_warning) = open_output(&session, sampler)?;
+    // the output warning stays as long as the output does
+    let warning = join_warnings(warning, output_warning.clone());
+
+    // Run without the TUI, the config is left untouched
+    if cli.headless {
+        if let Some(warning) = warning {
+            eprintln!("{}", warning);
+        }
+        output.play()?;
+        headless::run(param, commands, &sampler_event_receiver)?;
+        output.pause()?;
+        return Ok(());
+    }
 
     // Initialize TUI
     let backend = CrosstermBackend::new(std::io::stderr());
     let terminal = Terminal::new(backend)?;
     let ui_event_capturer = UIEventCapturer::new(20);
     let mut tui = Tui::new(terminal, ui_event_capturer);
-    let mut app = App::new(param.clone());
+    let mut app = App::new(param.clone(), commands);
+    app.bpm_step = session.bpm_step;
+    app.warning = warning;
+    app.kits = list_kits()?;
+    app.kit = kit.clone();
 
     tui.enter()?;
-    stream.play()?;
+    output.play()?;
     while !app.should_quit {
         // Render the user interface.
         tui.draw(&mut app)?;
 
-        // Audio event (try not to block)
-        match sampler_event_receiver.try_recv() {
-            Ok(ref e) => app.update_by_sampler_event(e),
-            _ => (),
+        // Audio events (try not to block), all of them so that they do not
+        // pile up when they come faster than the UI ticks
+        while let Ok(ref e) = sampler_event_receiver.try_recv() {
+            app.update_by_sampler_event(e);
         }
 
         // UI event
@@ -64,14 +126,107 @@ fn main() -> Result<()> {
         if let Some(ui_event) = app.map_input_event(&input_event) {
             app.update_by_ui_event(&ui_event);
         }
+
+        // Switch kits by swapping the sounds, the samples are prepared here
+        // so that the audio thread does not have to
+        if app.kit != kit {
+            kit = app.kit.clone();
+            let (mut sounds, warning) =
+                load_sounds(sample_path.as_deref(), kit.as_deref(), &session);
+            sounds.prepare(sample_rate);
+            app.warning = join_warnings(warning, output_warning.clone());
+            app.send(SamplerCommand::Sounds(Box::new(sounds)));
+        }
     }
-    stream.pause()?;
+    output.pause()?;
     tui.exit()?;
 
-    // update config and write
-    config.bpm = param.bpm.load(Ordering::Relaxed);
-    config.volume = param.volume.load(Ordering::Relaxed);
-    config.write()?;
+    // update config and write, values only given on the command line are
+    // not saved unless changed since
+    let bpm = app.bpm;
+    if cli.bpm != Some(bpm) {
+        config.bpm = bpm;
+    }
+    let volume = param.volume.load(Ordering::Relaxed);
+    if cli.volume != Some(volume) {
+        config.volume = volume;
+    }
+    let total_beats = app.total_beats;
+    if cli.beats != Some(total_beats) {
+        config.total_beats = total_beats;
+    }
+    let subdivision = param.subdivision.load(Ordering::Relaxed);
+    if cli.subdivision != Some(subdivision) {
+        config.subdivision = subdivision;
+    }
+    config.bpm_step = app.bpm_step;
+    config.tempo_change = param.tempo_change.load(Ordering::Relaxed);
+    config.pan = param.pan.load(Ordering::Relaxed);
+    config.swing = param.swing.load(Ordering::Relaxed);
+    config.pattern = app.pattern.to_vec();
+    config.polyrhythm = param.polyrhythm.load(Ordering::Relaxed);
+    config.poly_beats = param.poly_beats.load(Ordering::Relaxed);
+    config.main_gain = param.main_gain.load(Ordering::Relaxed);
+    config.poly_gain = param.poly_gain.load(Ordering::Relaxed);
+    config.trainer = param.trainer.load(Ordering::Relaxed);
+    config.gap_mode = param.gap_mode.load(Ordering::Relaxed);
+    config.count_in_bars = param.count_in_bars.load(Ordering::Relaxed);
+    // a kit only given on the command line is not saved
+    if cli.kit.is_none() || kit != cli.kit {
+        config.kit = kit;
+    }
+    if !cli.no_save {
+        config.write(&config_path)?;
+    }
 
     Ok(())
 }
+
+/// Loads the sounds from a kit, a sample file, or the sound of the config,
+/// in that order of precedence. The accent sample of the config replaces
+/// the accent unless a kit is given.
+///
+/// A sample or kit that fails to load falls back to the built-in click, and
+/// an accent sample to the usual accent, the returned warning tells why.
+fn load_sounds(
+    sample_path: Option<&str>,
+    kit: Option<&str>,
+    config: &CoryConfig,
+) -> (Sounds, Option<String>) {
+    let loaded = if let Some(name) = kit {
+        Kit::load_by_name(name).map(Sounds::from_kit)
+    } else if let Some(path) = sample_path {
+        Sample::from_path(path).map(Sounds::from_sample)
+    } else {
+        Ok(match config.sound {
+            SoundSource::Sample => Sounds::builtin(),
+            SoundSource::Synth => Sounds::from_synth(&config.synth),
+        })
+    };
+    let (mut sounds, mut warning) = match loaded {
+        Ok(sounds) => (sounds, None),
+        Err(e) => (
+            Sounds::builtin(),
+            Some(format!("{:#}, using the built-in click", e)),
+        ),
+    };
+
+    if let (None, Some(path)) = (kit, config.accent_sample.as_deref()) {
+        match Sample::from_path(path) {
+            Ok(sample) => sounds.accent = Some(sample),
+            Err(e) => {
+                let accent_warning = format!("{:#}, using the usual accent", e);
+                warning = join_warnings(warning, Some(accent_warning));
+            }
+        }
+    }
+    (sounds, warning)
+}
+
+/// Shows both warnings when there are two.
+fn join_warnings(first: Option<String>, second: Option<String>) -> Option<String> {
+    match (first, second) {
+        (Some(first), Some(second)) => Some(format!("{}; {}", first, second)),
+        (first, second) => first.or(second),
+    }
+}
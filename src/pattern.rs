@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::MAX_TOTAL_BEATS;
+
+/// How a single beat of the bar is played
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BeatKind {
+    Accent,
+    Normal,
+    Ghost,
+    Mute,
+}
+
+impl BeatKind {
+    /// The next kind when cycling through them in the editor
+    pub fn next(self) -> Self {
+        match self {
+            Self::Accent => Self::Normal,
+            Self::Normal => Self::Ghost,
+            Self::Ghost => Self::Mute,
+            Self::Mute => Self::Accent,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::Accent => "A",
+            Self::Normal => "N",
+            Self::Ghost => "g",
+            Self::Mute => "-",
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Accent => 0,
+            Self::Normal => 1,
+            Self::Ghost => 2,
+            Self::Mute => 3,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Accent,
+            2 => Self::Ghost,
+            3 => Self::Mute,
+            _ => Self::Normal,
+        }
+    }
+}
+
+/// The kind of every beat a bar can have
+pub type Pattern = [BeatKind; MAX_TOTAL_BEATS as usize];
+
+/// The default pattern: accent on the downbeat, normal beats elsewhere
+pub fn default_pattern() -> Vec<BeatKind> {
+    (0..MAX_TOTAL_BEATS)
+        .map(|i| {
+            if i == 0 {
+                BeatKind::Accent
+            } else {
+                BeatKind::Normal
+            }
+        })
+        .collect()
+}
+
+/// A beat pattern that can be shared with the audio thread.
+///
+/// It always holds `MAX_TOTAL_BEATS` beats, only the first `total_beats`
+/// of them are played.
+#[derive(Debug)]
+pub struct AtomicPattern {
+    beats: [AtomicU8; MAX_TOTAL_BEATS as usize],
+}
+
+impl AtomicPattern {
+    /// Missing beats are filled with the default pattern.
+    pub fn new(pattern: &[BeatKind]) -> Self {
+        let default = default_pattern();
+        Self {
+            beats: std::array::from_fn(|i| {
+                let kind = pattern.get(i).copied().unwrap_or(default[i]);
+                AtomicU8::new(kind.to_u8())
+            }),
+        }
+    }
+
+    pub fn load(&self, beat: u32, ordering: Ordering) -> BeatKind {
+        match self.beats.get(beat as usize) {
+            Some(x) => BeatKind::from_u8(x.load(ordering)),
+            None => BeatKind::Normal,
+        }
+    }
+
+    pub fn store(&self, beat: u32, kind: BeatKind, ordering: Ordering) {
+        if let Some(x) = self.beats.get(beat as usize) {
+            x.store(kind.to_u8(), ordering);
+        }
+    }
+
+    /// Replaces every beat, each one is stored separately.
+    pub fn store_all(&self, pattern: &Pattern, ordering: Ordering) {
+        for (beat, kind) in pattern.iter().enumerate() {
+            self.store(beat as u32, *kind, ordering);
+        }
+    }
+
+    pub fn to_array(&self, ordering: Ordering) -> Pattern {
+        std::array::from_fn(|i| self.load(i as u32, ordering))
+    }
+}
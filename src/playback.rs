@@ -1,52 +1,407 @@
-use cpal::{traits::DeviceTrait, FromSample, SizedSample};
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    FromSample, SizedSample,
+};
+use eyre::{eyre, Result, WrapErr};
 
 use std::fmt::Debug;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+use crate::config::CoryConfig;
 use crate::sampler::Sampler;
 
+// the null output stands in for a device with these settings unless
+// configured otherwise
+const NULL_SAMPLE_RATE: u32 = 48000;
+const NULL_BUFFER_SIZE: u32 = 512;
+const NULL_CHANNELS: u16 = 2;
+// a null output running later than this skips ahead instead of catching up
+const NULL_MAX_LATENESS: Duration = Duration::from_millis(200);
+
+/// Where the sampler is played
+pub enum Output {
+    Device(cpal::Stream),
+    Null(NullOutput),
+}
+
+impl Output {
+    pub fn play(&self) -> Result<()> {
+        match self {
+            Self::Device(stream) => stream.play()?,
+            Self::Null(output) => output.playing.store(true, Ordering::Relaxed),
+        }
+        Ok(())
+    }
+
+    pub fn pause(&self) -> Result<()> {
+        match self {
+            Self::Device(stream) => stream.pause()?,
+            Self::Null(output) => output.playing.store(false, Ordering::Relaxed),
+        }
+        Ok(())
+    }
+}
+
+/// Drives the sampler from a timer as often as a device would, what it
+/// writes is discarded.
+///
+/// The events of the sampler keep coming without any sound hardware.
+pub struct NullOutput {
+    playing: Arc<AtomicBool>,
+    quit: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NullOutput {
+    pub fn new(mut sampler: Sampler, sample_rate: u32, buffer_size: u32) -> Self {
+        sampler.prepare(sample_rate);
+        let playing = Arc::new(AtomicBool::new(false));
+        let quit = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let playing = playing.clone();
+            let quit = quit.clone();
+            let mut buffer = vec![0.0f32; buffer_size as usize * NULL_CHANNELS as usize];
+            thread::spawn(move || {
+                // the deadlines are counted in frames from the start so
+                // that rounding does not add up
+                let mut start = Instant::now();
+                let mut frames: u64 = 0;
+                while !quit.load(Ordering::Relaxed) {
+                    if !playing.load(Ordering::Relaxed) {
+                        thread::sleep(frames_to_duration(buffer_size as u64, sample_rate));
+                        start = Instant::now();
+                        frames = 0;
+                        continue;
+                    }
+                    sampler.write(&mut buffer, sample_rate, NULL_CHANNELS);
+                    frames += buffer_size as u64;
+                    let deadline = start + frames_to_duration(frames, sample_rate);
+                    let now = Instant::now();
+                    if now > deadline + NULL_MAX_LATENESS {
+                        start = now;
+                        frames = 0;
+                    } else if now < deadline {
+                        thread::sleep(deadline - now);
+                    }
+                }
+            })
+        };
+
+        Self {
+            playing,
+            quit,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for NullOutput {
+    fn drop(&mut self) {
+        self.quit.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn frames_to_duration(frames: u64, sample_rate: u32) -> Duration {
+    Duration::from_nanos(frames * 1_000_000_000 / sample_rate as u64)
+}
+
+/// Builds the output stream, the sampler is taken only if it succeeds.
 pub fn get_stream<T>(
     device: &cpal::Device,
     config: &cpal::StreamConfig,
-    mut sampler: Sampler,
-) -> cpal::Stream
+    sampler: &mut Option<Sampler>,
+) -> Result<cpal::Stream>
 where
     T: SizedSample + FromSample<f64> + Debug,
 {
     let sample_rate: u32 = config.sample_rate.0;
     let channels: u16 = config.channels;
+    let mut prepared = sampler.take().ok_or_else(|| eyre!("No sampler to play"))?;
+    prepared.prepare(sample_rate);
 
-    let stream = device
-        .build_output_stream(
-            config,
+    // the callback takes the sampler on its first call, until then it can
+    // be given back when the stream fails to build
+    let handover = Arc::new(Mutex::new(Some(prepared)));
+    let mut callback_sampler = None;
+    let result = device.build_output_stream(
+        config,
+        {
+            let handover = handover.clone();
             move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-                sampler.write(data, sample_rate, channels);
-            },
-            |err| {
-                eprintln!("an error occurred on stream: {}", err);
-            },
-            None,
-        )
-        .unwrap();
-    stream
-}
-
-pub fn init_stream(device: &cpal::Device, sampler: Sampler) -> cpal::Stream {
-    let config = device.default_output_config().unwrap();
-    match config.sample_format() {
-        cpal::SampleFormat::I8 => get_stream::<i8>(&device, &config.into(), sampler),
-        cpal::SampleFormat::I16 => get_stream::<i16>(&device, &config.into(), sampler),
-        // cpal::SampleFormat::I24 => run::<I24>(&device, &config.into()),
-        cpal::SampleFormat::I32 => get_stream::<i32>(&device, &config.into(), sampler),
-        // cpal::SampleFormat::I48 => run::<I48>(&device, &config.into()),
-        cpal::SampleFormat::I64 => get_stream::<i64>(&device, &config.into(), sampler),
-        cpal::SampleFormat::U8 => get_stream::<u8>(&device, &config.into(), sampler),
-        cpal::SampleFormat::U16 => get_stream::<u16>(&device, &config.into(), sampler),
-        // cpal::SampleFormat::U24 => run::<U24>(&device, &config.into()),
-        cpal::SampleFormat::U32 => get_stream::<u32>(&device, &config.into(), sampler),
-        // cpal::SampleFormat::U48 => run::<U48>(&device, &config.into()),
-        cpal::SampleFormat::U64 => get_stream::<u64>(&device, &config.into(), sampler),
-        cpal::SampleFormat::F32 => get_stream::<f32>(&device, &config.into(), sampler),
-        cpal::SampleFormat::F64 => get_stream::<f64>(&device, &config.into(), sampler),
-        sample_format => panic!("Unsupported sample format '{sample_format}'"),
+                if callback_sampler.is_none() {
+                    if let Ok(mut handover) = handover.try_lock() {
+                        callback_sampler = handover.take();
+                    }
+                }
+                match callback_sampler {
+                    Some(ref mut sampler) => sampler.write(data, sample_rate, channels),
+                    None => data.fill(T::EQUILIBRIUM),
+                }
+            }
+        },
+        |err| {
+            eprintln!("an error occurred on stream: {}", err);
+        },
+        None,
+    );
+    if result.is_err() {
+        *sampler = handover.lock().ok().and_then(|mut x| x.take());
+    }
+    Ok(result?)
+}
+
+/// The audio host with the given name or index, or the default one.
+pub fn find_host(name: Option<&str>) -> Result<cpal::Host> {
+    let name = match name {
+        Some(name) => name,
+        None => return Ok(cpal::default_host()),
+    };
+    let host_ids = cpal::available_hosts();
+    let host_id = match name.parse::<usize>() {
+        Ok(idx) => host_ids.get(idx).copied(),
+        Err(_) => host_ids
+            .iter()
+            .find(|x| x.name().eq_ignore_ascii_case(name))
+            .copied(),
+    };
+    let host_id = host_id.ok_or_else(|| eyre!("No audio host '{}'", name))?;
+    cpal::host_from_id(host_id).wrap_err_with(|| format!("Audio host '{}' is unavailable", name))
+}
+
+/// The output device with the given name or index, or the default one.
+pub fn find_output_device(host: &cpal::Host, name: Option<&str>) -> Result<cpal::Device> {
+    match name {
+        Some(name) => {
+            let mut devices = host.output_devices()?;
+            let device = match name.parse::<usize>() {
+                Ok(idx) => devices.nth(idx),
+                Err(_) => devices.find(|x| x.name().is_ok_and(|x| x == name)),
+            };
+            device.ok_or_else(|| eyre!("No output device '{}' on {}", name, host.id().name()))
+        }
+        None => host
+            .default_output_device()
+            .ok_or_else(|| eyre!("No default output device on {}", host.id().name())),
+    }
+}
+
+/// Names of the output devices of every available host, along with the
+/// name of the host and whether the device is its default one.
+///
+/// The hosts and devices are in the order their indices refer to, a host
+/// that fails to open has no device.
+pub fn list_output_devices() -> Vec<(String, Vec<(String, bool)>)> {
+    cpal::available_hosts()
+        .into_iter()
+        .map(|id| {
+            let devices = cpal::host_from_id(id)
+                .map(|host| list_host_output_devices(&host))
+                .unwrap_or_default();
+            (id.name().to_string(), devices)
+        })
+        .collect()
+}
+
+fn list_host_output_devices(host: &cpal::Host) -> Vec<(String, bool)> {
+    let default_name = host.default_output_device().and_then(|x| x.name().ok());
+    match host.output_devices() {
+        Ok(devices) => devices
+            .map(|x| x.name().unwrap_or_else(|_| "unknown".to_string()))
+            .map(|name| {
+                let is_default = default_name.as_ref() == Some(&name);
+                (name, is_default)
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Opens the output of the config along with its sample rate.
+///
+/// Without a configured device, a missing default device or one that fails
+/// to open falls back to the null output, the returned warning tells why.
+/// Settings the device does not support are errors either way.
+pub fn open_output(config: &CoryConfig, sampler: Sampler) -> Result<(Output, u32, Option<String>)> {
+    let host = find_host(config.host.as_deref())?;
+    let device = find_output_device(&host, config.device.as_deref()).and_then(|device| {
+        let default_config = device.default_output_config().wrap_err_with(|| {
+            let name = device.name().unwrap_or_else(|_| "unknown".to_string());
+            format!("Unable to configure output device '{}'", name)
+        })?;
+        Ok((device, default_config))
+    });
+    let (device, default_config) = match device {
+        Ok(device) => device,
+        Err(e) if config.device.is_none() => return Ok(null_output(config, sampler, e)),
+        Err(e) => return Err(e),
+    };
+
+    let (sample_format, stream_config) = init_stream_config(
+        &device,
+        default_config,
+        config.sample_rate,
+        config.buffer_size,
+    )?;
+    let mut sampler = Some(sampler);
+    let stream = init_stream(&device, sample_format, &stream_config, &mut sampler);
+    match (stream, sampler) {
+        (Ok(stream), _) => Ok((Output::Device(stream), stream_config.sample_rate.0, None)),
+        (Err(e), Some(sampler)) if config.device.is_none() => Ok(null_output(config, sampler, e)),
+        (Err(e), _) => Err(e),
+    }
+}
+
+fn null_output(
+    config: &CoryConfig,
+    sampler: Sampler,
+    reason: eyre::Report,
+) -> (Output, u32, Option<String>) {
+    let sample_rate = config.sample_rate.unwrap_or(NULL_SAMPLE_RATE);
+    let buffer_size = config.buffer_size.unwrap_or(NULL_BUFFER_SIZE);
+    let output = NullOutput::new(sampler, sample_rate, buffer_size);
+    let warning = format!("{:#}, playing without sound", reason);
+    (Output::Null(output), sample_rate, Some(warning))
+}
+
+/// The sample format and config of the output stream.
+///
+/// The sample rate and the buffer size in frames are the ones of the
+/// default config of the device unless given.
+pub fn init_stream_config(
+    device: &cpal::Device,
+    default_config: cpal::SupportedStreamConfig,
+    sample_rate: Option<u32>,
+    buffer_size: Option<u32>,
+) -> Result<(cpal::SampleFormat, cpal::StreamConfig)> {
+    let device_name = device.name().unwrap_or_else(|_| "unknown".to_string());
+    let supported_config = match sample_rate {
+        Some(rate) => device
+            .supported_output_configs()?
+            .filter(|x| x.min_sample_rate().0 <= rate && rate <= x.max_sample_rate().0)
+            // keep the channels and then the sample format of the device if
+            // possible
+            .max_by_key(|x| {
+                (
+                    x.channels() == default_config.channels(),
+                    x.sample_format() == default_config.sample_format(),
+                )
+            })
+            .map(|x| x.with_sample_rate(cpal::SampleRate(rate)))
+            .ok_or_else(|| {
+                eyre!(
+                    "Output device '{}' does not support a sample rate of {} Hz",
+                    device_name,
+                    rate
+                )
+            })?,
+        None => default_config,
+    };
+
+    let mut config: cpal::StreamConfig = supported_config.config();
+    if let Some(frames) = buffer_size {
+        if let cpal::SupportedBufferSize::Range { min, max } = supported_config.buffer_size() {
+            if frames < *min || frames > *max {
+                return Err(eyre!(
+                    "Output device '{}' only supports buffer sizes from {} to {} frames",
+                    device_name,
+                    min,
+                    max
+                ));
+            }
+        }
+        config.buffer_size = cpal::BufferSize::Fixed(frames);
+    }
+
+    let sample_format = supported_config.sample_format();
+    if !is_playable_format(sample_format) {
+        return Err(eyre!(
+            "Output device '{}' uses the unsupported sample format '{}'",
+            device_name,
+            sample_format
+        ));
+    }
+    Ok((sample_format, config))
+}
+
+fn is_playable_format(sample_format: cpal::SampleFormat) -> bool {
+    use cpal::SampleFormat::*;
+    matches!(
+        sample_format,
+        I8 | I16 | I32 | I64 | U8 | U16 | U32 | U64 | F32 | F64
+    )
+}
+
+/// Builds the output stream, the sampler is taken only if it succeeds.
+pub fn init_stream(
+    device: &cpal::Device,
+    sample_format: cpal::SampleFormat,
+    config: &cpal::StreamConfig,
+    sampler: &mut Option<Sampler>,
+) -> Result<cpal::Stream> {
+    let device_name = device.name().unwrap_or_else(|_| "unknown".to_string());
+    let stream = match sample_format {
+        cpal::SampleFormat::I8 => get_stream::<i8>(device, config, sampler),
+        cpal::SampleFormat::I16 => get_stream::<i16>(device, config, sampler),
+        // cpal::SampleFormat::I24 => run::<I24>(device, &config.into()),
+        cpal::SampleFormat::I32 => get_stream::<i32>(device, config, sampler),
+        // cpal::SampleFormat::I48 => run::<I48>(device, &config.into()),
+        cpal::SampleFormat::I64 => get_stream::<i64>(device, config, sampler),
+        cpal::SampleFormat::U8 => get_stream::<u8>(device, config, sampler),
+        cpal::SampleFormat::U16 => get_stream::<u16>(device, config, sampler),
+        // cpal::SampleFormat::U24 => run::<U24>(device, &config.into()),
+        cpal::SampleFormat::U32 => get_stream::<u32>(device, config, sampler),
+        // cpal::SampleFormat::U48 => run::<U48>(device, &config.into()),
+        cpal::SampleFormat::U64 => get_stream::<u64>(device, config, sampler),
+        cpal::SampleFormat::F32 => get_stream::<f32>(device, config, sampler),
+        cpal::SampleFormat::F64 => get_stream::<f64>(device, config, sampler),
+        sample_format => Err(eyre!("Unsupported sample format '{sample_format}'")),
+    }
+    .wrap_err_with(|| format!("Unable to open output device '{}'", device_name))?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::{SamplerEvent, SamplerParam, Sounds};
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn null_output_sends_events_in_time() {
+        let config = CoryConfig {
+            bpm: 600.0,
+            max_bpm: 600.0,
+            ..Default::default()
+        };
+        let param = Arc::new(SamplerParam::from_config(&config));
+        let (sender, receiver) = channel();
+        let sampler = Sampler::new(Sounds::builtin(), param, Some(sender));
+        let output = Output::Null(NullOutput::new(sampler, 48000, 256));
+
+        // nothing is written while paused
+        assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+
+        output.play().unwrap();
+        let start = Instant::now();
+        let mut beats = Vec::new();
+        while beats.len() < 4 {
+            let event = receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+            if let SamplerEvent::Beat { beat, .. } = event {
+                beats.push(beat);
+            }
+        }
+        assert_eq!(beats, [0, 1, 2, 3]);
+        // the fourth beat is 300 ms in at 600 BPM, not as fast as possible
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        output.pause().unwrap();
     }
 }
@@ -0,0 +1,60 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use eyre::{Result, WrapErr};
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use crate::cli::RenderArgs;
+use crate::clock::Clock;
+use crate::config::CoryConfig;
+use crate::sampler::{GapMode, Sampler, SamplerParam, Sounds};
+
+// frames rendered by each call to `Sampler::write`, like an audio callback
+const BLOCK_FRAMES: usize = 512;
+// used when no sample rate is configured, there is no device to ask
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+/// Writes a mono click track, the count-in of the config comes first.
+///
+/// The practice modes are ignored so that every bar is played at the same
+/// tempo: the trainer and the gap mode are off.
+pub fn render(args: &RenderArgs, config: &CoryConfig, sounds: Sounds) -> Result<()> {
+    let param = Arc::new(SamplerParam::from_config(config));
+    param.playing.store(true, Ordering::Relaxed);
+    param.trainer.store(false, Ordering::Relaxed);
+    param.gap_mode.store(GapMode::Off, Ordering::Relaxed);
+    let sample_rate = config.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+    let mut sampler = Sampler::new(sounds, param, None);
+    sampler.prepare(sample_rate);
+
+    let spec = WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: args.bit_depth,
+        sample_format: match args.bit_depth {
+            32 => SampleFormat::Float,
+            _ => SampleFormat::Int,
+        },
+    };
+    let mut writer = WavWriter::create(&args.output, spec)
+        .wrap_err_with(|| format!("Unable to create '{}'", args.output))?;
+
+    let n_beats = (config.count_in_bars + args.bars) as u64 * config.total_beats as u64;
+    let mut n_frames = Clock::new(sample_rate).frames_until(n_beats, 1, config.bpm);
+    let mut block = [0.0f32; BLOCK_FRAMES];
+    while n_frames > 0 {
+        let len = (n_frames as usize).min(BLOCK_FRAMES);
+        sampler.write(&mut block[..len], sample_rate, 1);
+        for value in &block[..len] {
+            let value = value.clamp(-1.0, 1.0);
+            match args.bit_depth {
+                16 => writer.write_sample((value * i16::MAX as f32) as i16)?,
+                24 => writer.write_sample((value * 8388607.0) as i32)?,
+                _ => writer.write_sample(value)?,
+            }
+        }
+        n_frames -= len as u64;
+    }
+    writer
+        .finalize()
+        .wrap_err_with(|| format!("Unable to write '{}'", args.output))
+}
@@ -0,0 +1,404 @@
+use eyre::{eyre, Result, WrapErr};
+use hound::{SampleFormat, WavReader};
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+// number of zero crossings on each side of the resampling kernel
+const SINC_ZEROS: usize = 16;
+// keeps the transition band of the resampling filter below Nyquist
+const SINC_ROLLOFF: f64 = 0.95;
+
+#[derive(Debug, Clone)]
+pub struct Sample {
+    // interleaved
+    samples: Vec<f64>,
+    n_channels: u16,
+    sample_rate: u32,
+}
+
+impl Sample {
+    pub fn new(samples: Vec<f64>, n_channels: u16, sample_rate: u32) -> Self {
+        Self {
+            samples,
+            n_channels,
+            sample_rate,
+        }
+    }
+
+    /// The samples of all channels at a given frame
+    pub fn frame(&self, idx: usize) -> Option<&[f64]> {
+        let n_channels = self.n_channels as usize;
+        self.samples.get(idx * n_channels..(idx + 1) * n_channels)
+    }
+
+    pub fn n_frames(&self) -> usize {
+        self.samples.len() / (self.n_channels as usize).max(1)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Loads a WAV, FLAC or Ogg Vorbis file.
+    ///
+    /// The format is detected from the magic bytes, or from the extension
+    /// if they are not recognised.
+    pub fn from_path<P: AsRef<Path>>(file_path: P) -> Result<Self> {
+        let file_path = file_path.as_ref();
+        let mut file = File::open(file_path)
+            .wrap_err_with(|| format!("Unable to open sample file '{}'", file_path.display()))?;
+        let mut magic = [0u8; 4];
+        let n_read = file.read(&mut magic)?;
+        file.seek(SeekFrom::Start(0))?;
+
+        let format = AudioFormat::detect(&magic[..n_read], file_path).ok_or_else(|| {
+            eyre!(
+                "Unable to recognise the format of '{}', expected WAV, FLAC or Ogg Vorbis",
+                file_path.display()
+            )
+        })?;
+        let reader = BufReader::new(file);
+        let sample = match format {
+            AudioFormat::Wav => WavReader::new(reader)
+                .map_err(eyre::Report::from)
+                .and_then(|mut x| Self::from_reader(&mut x)),
+            AudioFormat::Flac => Self::from_flac_reader(reader),
+            AudioFormat::Vorbis => Self::from_vorbis_reader(reader),
+        };
+        sample.wrap_err_with(|| {
+            format!(
+                "Unable to load '{}' as {}",
+                file_path.display(),
+                format.name()
+            )
+        })
+    }
+
+    pub fn from_flac_reader<R: Read>(reader: R) -> Result<Self> {
+        let mut reader = claxon::FlacReader::new(reader)?;
+        let info = reader.streaminfo();
+        let max_value = match info.bits_per_sample {
+            bits @ 8..=32 => ((1u64 << (bits - 1)) - 1) as f64,
+            _ => return Err(eyre!("Not supported bit depth")),
+        };
+        let samples = reader
+            .samples()
+            .map(|x| x.map(|x| x as f64 / max_value))
+            .collect::<Result<Vec<f64>, _>>()?;
+        Ok(Self {
+            samples,
+            n_channels: info.channels as u16,
+            sample_rate: info.sample_rate,
+        })
+    }
+
+    pub fn from_vorbis_reader<R: Read + Seek>(reader: R) -> Result<Self> {
+        let mut reader = lewton::inside_ogg::OggStreamReader::new(reader)?;
+        let mut samples = Vec::new();
+        while let Some(packet) = reader.read_dec_packet_itl()? {
+            samples.extend(packet.iter().map(|x| *x as f64 / i16::MAX as f64));
+        }
+        let n_channels = reader.ident_hdr.audio_channels as usize;
+        // the channels are put in the WAV order the sampler mixes from
+        if let Some(order) = vorbis_channel_order(n_channels) {
+            let mut frame = vec![0.0; n_channels];
+            for chunk in samples.chunks_exact_mut(n_channels) {
+                for (value, &idx) in frame.iter_mut().zip(order) {
+                    *value = chunk[idx];
+                }
+                chunk.copy_from_slice(&frame);
+            }
+        }
+        Ok(Self {
+            samples,
+            n_channels: n_channels as u16,
+            sample_rate: reader.ident_hdr.audio_sample_rate,
+        })
+    }
+
+    /// Multiplies every sample by `gain`.
+    pub fn scaled(mut self, gain: f64) -> Self {
+        for value in self.samples.iter_mut() {
+            *value *= gain;
+        }
+        self
+    }
+
+    pub fn from_reader<R: io::Read>(reader: &mut WavReader<R>) -> Result<Self> {
+        let spec = reader.spec();
+        let bit_depth = spec.bits_per_sample;
+
+        let samples: Vec<f64> = match spec.sample_format {
+            SampleFormat::Float => {
+                let buffer_in = read_samples_to_buffer::<f32, _>(reader)?;
+                let mut buffer_out = vec![0.0; buffer_in.len()];
+                buffer_f32_to_f64(&buffer_in, &mut buffer_out)?;
+                buffer_out
+            }
+            SampleFormat::Int => match bit_depth {
+                16 => {
+                    let buffer_in = read_samples_to_buffer::<i16, _>(reader)?;
+                    let mut buffer_out = vec![0.0; buffer_in.len()];
+                    buffer_i16_to_f64(&buffer_in, bit_depth, &mut buffer_out)?;
+                    Ok(buffer_out)
+                }
+                24 | 32 => {
+                    let buffer_in = read_samples_to_buffer::<i32, _>(reader)?;
+                    let mut buffer_out = vec![0.0; buffer_in.len()];
+                    buffer_i32_to_f64(&buffer_in, bit_depth, &mut buffer_out)?;
+                    Ok(buffer_out)
+                }
+                _ => Err(eyre!("Unsupported integer sample format bit depth")),
+            }?,
+        };
+
+        Ok(Self {
+            samples,
+            n_channels: spec.channels,
+            sample_rate: spec.sample_rate,
+        })
+    }
+
+    /// Resamples to another sample rate with a windowed sinc filter.
+    ///
+    /// The cutoff sits just below the lower of the two Nyquist frequencies,
+    /// so downsampling does not alias.
+    pub fn resample(&self, sample_rate: u32) -> Self {
+        if sample_rate == self.sample_rate || self.samples.is_empty() {
+            return self.clone();
+        }
+
+        // input frames per output frame
+        let ratio = self.sample_rate as f64 / sample_rate as f64;
+        let cutoff = (1.0 / ratio).min(1.0) * SINC_ROLLOFF;
+        let half_width = SINC_ZEROS as f64 / cutoff;
+        let n_channels = self.n_channels as usize;
+        let n_frames_in = self.n_frames();
+        let n_frames_out = (n_frames_in as f64 / ratio).ceil() as usize;
+
+        let mut samples = vec![0.0; n_frames_out * n_channels];
+        for (idx, frame_out) in samples.chunks_mut(n_channels).enumerate() {
+            let center = idx as f64 * ratio;
+            let start = (center - half_width).ceil().max(0.0) as usize;
+            let end = ((center + half_width).floor() as usize).min(n_frames_in - 1);
+            for k in start..=end {
+                let weight = sinc_kernel(center - k as f64, cutoff, half_width);
+                let frame_in = &self.samples[k * n_channels..(k + 1) * n_channels];
+                for (value_out, value_in) in frame_out.iter_mut().zip(frame_in) {
+                    *value_out += value_in * weight;
+                }
+            }
+        }
+
+        Self {
+            samples,
+            n_channels: self.n_channels,
+            sample_rate,
+        }
+    }
+}
+
+/// The file formats a sample can be loaded from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AudioFormat {
+    Wav,
+    Flac,
+    Vorbis,
+}
+
+impl AudioFormat {
+    fn detect(magic: &[u8], file_path: &Path) -> Option<Self> {
+        match magic {
+            b"RIFF" | b"RIFX" | b"RF64" => return Some(Self::Wav),
+            b"fLaC" => return Some(Self::Flac),
+            b"OggS" => return Some(Self::Vorbis),
+            _ => (),
+        }
+        let extension = file_path.extension()?.to_string_lossy().to_lowercase();
+        match extension.as_str() {
+            "wav" | "wave" => Some(Self::Wav),
+            "flac" => Some(Self::Flac),
+            "ogg" | "oga" => Some(Self::Vorbis),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Wav => "WAV",
+            Self::Flac => "FLAC",
+            Self::Vorbis => "Ogg Vorbis",
+        }
+    }
+}
+
+/// The Vorbis channel of every WAV channel, for the layouts where they differ
+fn vorbis_channel_order(n_channels: usize) -> Option<&'static [usize]> {
+    match n_channels {
+        3 => Some(&[0, 2, 1]),
+        5 => Some(&[0, 2, 1, 3, 4]),
+        6 => Some(&[0, 2, 1, 5, 3, 4]),
+        7 => Some(&[0, 2, 1, 6, 5, 3, 4]),
+        8 => Some(&[0, 2, 1, 7, 5, 6, 3, 4]),
+        _ => None,
+    }
+}
+
+/// A low-pass sinc at `cutoff` (relative to the input Nyquist) with a Blackman window.
+fn sinc_kernel(x: f64, cutoff: f64, half_width: f64) -> f64 {
+    let u = x / half_width;
+    if u.abs() >= 1.0 {
+        return 0.0;
+    }
+    let window = 0.42 + 0.5 * (PI * u).cos() + 0.08 * (2.0 * PI * u).cos();
+    let y = PI * cutoff * x;
+    let sinc = if y == 0.0 { 1.0 } else { y.sin() / y };
+    cutoff * sinc * window
+}
+
+fn read_samples_to_buffer<T, R>(reader: &mut WavReader<R>) -> Result<Vec<T>>
+where
+    R: io::Read,
+    T: hound::Sample,
+{
+    Ok(reader.samples::<T>().collect::<Result<Vec<T>, _>>()?)
+}
+
+fn buffer_i32_to_f64(buffer_in: &[i32], bit_depth: u16, buffer_out: &mut [f64]) -> Result<()> {
+    let max_value = match bit_depth {
+        24 => Ok(((1 << 23) - 1) as f64),
+        32 => Ok(i32::MAX as f64),
+        _ => Err(eyre!("Not supported bit depth")),
+    }?;
+    for (sample_in, sample_out) in buffer_in.iter().zip(buffer_out.iter_mut()) {
+        let normalized_sample = *sample_in as f64 / max_value;
+        *sample_out = normalized_sample;
+    }
+    Ok(())
+}
+
+fn buffer_i16_to_f64(buffer_in: &[i16], bit_depth: u16, buffer_out: &mut [f64]) -> Result<()> {
+    let max_value = match bit_depth {
+        16 => Ok(i16::MAX as f64),
+        _ => Err(eyre!("Not supported bit depth")),
+    }?;
+    for (sample_in, sample_out) in buffer_in.iter().zip(buffer_out.iter_mut()) {
+        let normalized_sample = *sample_in as f64 / max_value;
+        *sample_out = normalized_sample;
+    }
+    Ok(())
+}
+
+fn buffer_f32_to_f64(buffer_in: &[f32], buffer_out: &mut [f64]) -> Result<()> {
+    for (sample_in, sample_out) in buffer_in.iter().zip(buffer_out.iter_mut()) {
+        *sample_out = *sample_in as f64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequencies: &[f64], sample_rate: u32, n_frames: usize) -> Sample {
+        let mut samples = Vec::with_capacity(n_frames * frequencies.len());
+        for idx in 0..n_frames {
+            let t = idx as f64 / sample_rate as f64;
+            for frequency in frequencies {
+                samples.push((2.0 * PI * frequency * t).sin());
+            }
+        }
+        Sample {
+            samples,
+            n_channels: frequencies.len() as u16,
+            sample_rate,
+        }
+    }
+
+    /// The largest difference between the samples, ignoring the edges of the sample
+    fn max_error(a: &Sample, b: &Sample, margin: usize) -> f64 {
+        let n_channels = a.n_channels as usize;
+        let n_frames = a.n_frames().min(b.n_frames());
+        a.samples[margin * n_channels..(n_frames - margin) * n_channels]
+            .iter()
+            .zip(&b.samples[margin * n_channels..(n_frames - margin) * n_channels])
+            .map(|(x, y)| (x - y).abs())
+            .fold(0.0, f64::max)
+    }
+
+    fn rms(sample: &Sample, margin: usize) -> f64 {
+        let n_channels = sample.n_channels as usize;
+        let values =
+            &sample.samples[margin * n_channels..sample.samples.len() - margin * n_channels];
+        (values.iter().map(|x| x * x).sum::<f64>() / values.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn detect_format_by_magic_before_extension() {
+        let path = Path::new("click.wav");
+        assert_eq!(AudioFormat::detect(b"RIFF", path), Some(AudioFormat::Wav));
+        assert_eq!(AudioFormat::detect(b"fLaC", path), Some(AudioFormat::Flac));
+        assert_eq!(
+            AudioFormat::detect(b"OggS", path),
+            Some(AudioFormat::Vorbis)
+        );
+    }
+
+    #[test]
+    fn detect_format_by_extension() {
+        let magic = b"\0\0\0\0";
+        let detect = |name| AudioFormat::detect(magic, Path::new(name));
+        assert_eq!(detect("click.WAV"), Some(AudioFormat::Wav));
+        assert_eq!(detect("click.flac"), Some(AudioFormat::Flac));
+        assert_eq!(detect("click.ogg"), Some(AudioFormat::Vorbis));
+        assert_eq!(detect("click.mp3"), None);
+        assert_eq!(detect("click"), None);
+    }
+
+    #[test]
+    fn resample_same_rate_is_identity() {
+        let sample = sine(&[1000.0], 48000, 4800);
+        let resampled = sample.resample(48000);
+        assert_eq!(resampled.samples, sample.samples);
+    }
+
+    #[test]
+    fn resample_up_matches_reference() {
+        let sample = sine(&[1000.0], 44100, 4410);
+        let resampled = sample.resample(48000);
+        let reference = sine(&[1000.0], 48000, 4800);
+        assert_eq!(resampled.sample_rate, 48000);
+        assert_eq!(resampled.n_frames(), 4800);
+        assert!(max_error(&resampled, &reference, 64) < 1e-3);
+    }
+
+    #[test]
+    fn resample_down_matches_reference() {
+        let sample = sine(&[1000.0], 96000, 9600);
+        let resampled = sample.resample(44100);
+        let reference = sine(&[1000.0], 44100, 4410);
+        assert_eq!(resampled.n_frames(), 4410);
+        assert!(max_error(&resampled, &reference, 64) < 1e-3);
+    }
+
+    #[test]
+    fn resample_keeps_channels_apart() {
+        let sample = sine(&[1000.0, 3000.0], 44100, 4410);
+        let resampled = sample.resample(48000);
+        let reference = sine(&[1000.0, 3000.0], 48000, 4800);
+        assert_eq!(resampled.n_channels, 2);
+        assert!(max_error(&resampled, &reference, 64) < 1e-3);
+    }
+
+    #[test]
+    fn resample_down_does_not_alias() {
+        // 30 kHz is above the Nyquist frequency of 44.1 kHz, nearest-neighbour
+        // resampling would fold it down to 14.1 kHz at full amplitude
+        let sample = sine(&[30000.0], 96000, 9600);
+        let resampled = sample.resample(44100);
+        assert!(rms(&sample, 64) > 0.7);
+        assert!(rms(&resampled, 64) < 1e-2);
+    }
+}
@@ -1,129 +1,767 @@
 use cpal::{FromSample, SizedSample};
-use eyre::{eyre, Result};
-use hound::{SampleFormat, WavReader};
-use std::io::{self, BufReader};
-use std::sync::atomic::AtomicBool;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8};
 use std::sync::{atomic::Ordering, mpsc::Sender, Arc};
 
-use crate::utils::AtomicF64;
+use crate::clock::Clock;
+use crate::command::{command_queue, CommandReceiver, CommandSender, SamplerCommand};
+use crate::config::{CoryConfig, BPM_PRECISION};
+use crate::kit::Kit;
+use crate::pattern::{AtomicPattern, BeatKind};
+use crate::sample::Sample;
+use crate::synth::SynthKit;
+use crate::utils::{inc_by_precision, AtomicF64};
 
 const AUDIO_FILE: &[u8] = include_bytes!("../assets/click.wav");
+// playback rate of the normal sample when used for the polyrhythm pulses
+const POLY_PITCH: f64 = 0.75;
+// playback rate of the normal sample when used for the count-in
+const COUNT_IN_PITCH: f64 = 1.25;
+// maximum number of clicks ringing at the same time
+const MAX_VOICES: usize = 8;
+// length of the fade-out applied when a voice is cut
+const DECLICK_SECONDS: f64 = 0.003;
+// swing is counted in thousandths
+const SWING_SCALE: u64 = 1000;
 
 #[derive(Debug)]
 pub struct SamplerParam {
     pub bpm: AtomicF64,
+    pub min_bpm: AtomicF64,
+    pub max_bpm: AtomicF64,
+    pub tempo_change: AtomicTempoChange,
+    /// The BPM waiting for the next beat or bar, 0 if there is none
+    pub pending_bpm: AtomicF64,
     pub playing: AtomicBool,
     pub volume: AtomicF64,
+    /// Stereo balance from -1 (left) to 1 (right)
+    pub pan: AtomicF64,
+    pub total_beats: AtomicU32,
+    /// Gain multiplier applied to accented beats
+    pub accent_gain: AtomicF64,
+    /// Playback rate multiplier of the normal sample when used for accented beats
+    pub accent_pitch: AtomicF64,
+    /// Number of pulses each beat is divided into, 1 means no subdivision
+    pub subdivision: AtomicU32,
+    /// Gain multiplier applied to the pulses between beats
+    pub subdivision_gain: AtomicF64,
+    /// Ratio of a subdivision pair taken by its first pulse, 0.5 is straight
+    pub swing: AtomicF64,
+    /// Gain multiplier applied to ghost beats
+    pub ghost_gain: AtomicF64,
+    pub pattern: AtomicPattern,
+    /// Whether the second pulse stream is played
+    pub polyrhythm: AtomicBool,
+    /// Number of pulses of the second stream within a bar
+    pub poly_beats: AtomicU32,
+    /// Gain multiplier of the beats and their subdivisions
+    pub main_gain: AtomicF64,
+    /// Gain multiplier of the polyrhythm pulses
+    pub poly_gain: AtomicF64,
+    /// Whether the tempo trainer changes the BPM
+    pub trainer: AtomicBool,
+    /// BPM added or removed towards the target at each trainer step
+    pub trainer_step: AtomicF64,
+    /// Number of bars between two trainer steps
+    pub trainer_bars: AtomicU32,
+    pub trainer_target: AtomicF64,
+    pub gap_mode: AtomicGapMode,
+    /// Number of bars played before the silent bars in `GapMode::Gap`
+    pub gap_play_bars: AtomicU32,
+    /// Number of silent bars in `GapMode::Gap`
+    pub gap_mute_bars: AtomicU32,
+    /// Probability of a bar being silent in `GapMode::Random`
+    pub mute_probability: AtomicF64,
+    /// Number of bars counted in when the click starts
+    pub count_in_bars: AtomicU32,
+}
+
+impl SamplerParam {
+    /// The initial parameters
+    pub fn from_config(config: &CoryConfig) -> Self {
+        Self {
+            bpm: AtomicF64::new(config.bpm),
+            min_bpm: AtomicF64::new(config.min_bpm),
+            max_bpm: AtomicF64::new(config.max_bpm),
+            tempo_change: AtomicTempoChange::new(config.tempo_change),
+            pending_bpm: AtomicF64::new(0.0),
+            playing: AtomicBool::new(config.play_on_launch),
+            volume: AtomicF64::new(config.volume),
+            pan: AtomicF64::new(config.pan),
+            total_beats: AtomicU32::new(config.total_beats),
+            accent_gain: AtomicF64::new(config.accent_gain),
+            accent_pitch: AtomicF64::new(config.accent_pitch),
+            subdivision: AtomicU32::new(config.subdivision),
+            subdivision_gain: AtomicF64::new(config.subdivision_gain),
+            swing: AtomicF64::new(config.swing),
+            ghost_gain: AtomicF64::new(config.ghost_gain),
+            pattern: AtomicPattern::new(&config.pattern),
+            polyrhythm: AtomicBool::new(config.polyrhythm),
+            poly_beats: AtomicU32::new(config.poly_beats),
+            main_gain: AtomicF64::new(config.main_gain),
+            poly_gain: AtomicF64::new(config.poly_gain),
+            trainer: AtomicBool::new(config.trainer),
+            trainer_step: AtomicF64::new(config.trainer_step),
+            trainer_bars: AtomicU32::new(config.trainer_bars),
+            trainer_target: AtomicF64::new(config.trainer_target),
+            gap_mode: AtomicGapMode::new(config.gap_mode),
+            gap_play_bars: AtomicU32::new(config.gap_play_bars),
+            gap_mute_bars: AtomicU32::new(config.gap_mute_bars),
+            mute_probability: AtomicF64::new(config.mute_probability),
+            count_in_bars: AtomicU32::new(config.count_in_bars),
+        }
+    }
+}
+
+/// How bars are muted to practise internal time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GapMode {
+    Off,
+    /// A fixed number of played bars followed by a fixed number of silent bars
+    Gap,
+    /// Each bar is silent with a given probability
+    Random,
+}
+
+impl GapMode {
+    /// The next mode when cycling through them
+    pub fn next(self) -> Self {
+        match self {
+            Self::Off => Self::Gap,
+            Self::Gap => Self::Random,
+            Self::Random => Self::Off,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AtomicGapMode {
+    storage: AtomicU8,
+}
+
+impl AtomicGapMode {
+    pub fn new(mode: GapMode) -> Self {
+        Self {
+            storage: AtomicU8::new(mode as u8),
+        }
+    }
+    pub fn store(&self, mode: GapMode, ordering: Ordering) {
+        self.storage.store(mode as u8, ordering)
+    }
+    pub fn load(&self, ordering: Ordering) -> GapMode {
+        match self.storage.load(ordering) {
+            1 => GapMode::Gap,
+            2 => GapMode::Random,
+            _ => GapMode::Off,
+        }
+    }
+}
+
+/// When a new tempo takes effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TempoChange {
+    Immediate,
+    /// At the start of the next beat
+    Beat,
+    /// At the start of the next bar
+    Bar,
+}
+
+impl TempoChange {
+    /// The next mode when cycling through them
+    pub fn next(self) -> Self {
+        match self {
+            Self::Immediate => Self::Beat,
+            Self::Beat => Self::Bar,
+            Self::Bar => Self::Immediate,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AtomicTempoChange {
+    storage: AtomicU8,
+}
+
+impl AtomicTempoChange {
+    pub fn new(mode: TempoChange) -> Self {
+        Self {
+            storage: AtomicU8::new(mode as u8),
+        }
+    }
+    pub fn store(&self, mode: TempoChange, ordering: Ordering) {
+        self.storage.store(mode as u8, ordering)
+    }
+    pub fn load(&self, ordering: Ordering) -> TempoChange {
+        match self.storage.load(ordering) {
+            1 => TempoChange::Beat,
+            2 => TempoChange::Bar,
+            _ => TempoChange::Immediate,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum SamplerEvent {
-    Tick,
+    /// A beat has been triggered, `beat` and `bar` start from 0
+    ///
+    /// The bars are only counted once the count-in is over.
+    Beat {
+        beat: u32,
+        bar: u64,
+        muted: bool,
+        count_in: bool,
+    },
+    /// A polyrhythm pulse has been triggered, `pulse` starts from 0
+    PolyPulse { pulse: u32 },
+    /// The tempo trainer has changed the BPM at the start of a bar
+    TrainerStep,
+}
+
+/// Which sound a voice is playing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Click {
+    Normal,
+    Accent,
+    Ghost,
+    Subdivision,
+    Poly,
+    CountIn,
+}
+
+/// Which pulse stream a voice belongs to, for its gain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stream {
+    Main,
+    Poly,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+    click: Click,
+    stream: Stream,
+    playhead: f64,
+    // trigger order, the oldest voice is stolen when the pool is full
+    id: u64,
+    // remaining frames of the fade-out once the voice is cut
+    fade: Option<u32>,
+    // whether it plays the sounds replaced by the last swap
+    replaced: bool,
+}
+
+impl Voice {
+    fn fade_out(mut self, frames: u32) -> Self {
+        self.fade = Some(self.fade.unwrap_or(frames).min(frames));
+        self
+    }
+
+    fn replaced(mut self) -> Self {
+        self.replaced = true;
+        self
+    }
+}
+
+/// The samples of every kind of click
+#[derive(Debug, Clone)]
+pub struct Sounds {
+    pub normal: Sample,
+    // fall back to the normal sample if not given
+    pub accent: Option<Sample>,
+    pub subdivision: Option<Sample>,
+    pub poly: Option<Sample>,
+    // played by the count-in bars
+    pub count_in: Option<Sample>,
+}
+
+impl Sounds {
+    /// The built-in click for every kind of click
+    pub fn builtin() -> Self {
+        // It must success
+        let mut reader = hound::WavReader::new(AUDIO_FILE).unwrap();
+        Self::from_sample(Sample::from_reader(&mut reader).unwrap())
+    }
+
+    pub fn from_sample(sample: Sample) -> Self {
+        Self {
+            normal: sample,
+            accent: None,
+            subdivision: None,
+            poly: None,
+            count_in: None,
+        }
+    }
+
+    /// Synthesized clicks instead of samples
+    pub fn from_synth(kit: &SynthKit) -> Self {
+        Self {
+            normal: kit.normal.render(),
+            accent: Some(kit.accent.render()),
+            subdivision: Some(kit.subdivision.render()),
+            poly: Some(kit.poly.render()),
+            count_in: None,
+        }
+    }
+
+    /// The samples of a sound kit
+    pub fn from_kit(kit: Kit) -> Self {
+        Self {
+            normal: kit.normal,
+            accent: kit.accent,
+            subdivision: kit.subdivision,
+            poly: None,
+            count_in: kit.count_in,
+        }
+    }
+
+    /// Resamples all the samples to the sample rate of the output stream.
+    ///
+    /// This should be done before they are played, the samples are
+    /// otherwise played back with linear interpolation.
+    pub fn prepare(&mut self, sample_rate: u32) {
+        self.normal = self.normal.resample(sample_rate);
+        for sample in [
+            &mut self.accent,
+            &mut self.subdivision,
+            &mut self.poly,
+            &mut self.count_in,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *sample = sample.resample(sample_rate);
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Sampler {
-    // buffer
-    samples: Vec<f64>,
-    #[allow(dead_code)]
-    n_channels: u16,
-    sample_rate: u32,
+    sounds: Sounds,
     // parameter
     param: Arc<SamplerParam>,
     // event sender (optional)
     sender: Option<Sender<SamplerEvent>>,
+    // command receiver (optional)
+    commands: Option<CommandReceiver>,
+    // the applied command holding the replaced sounds, sent back once the
+    // voices playing them have faded out
+    replaced_sounds: Option<SamplerCommand>,
     // internal states
-    playhead: f64,
+    voices: [Option<Voice>; MAX_VOICES],
+    // stolen voices fading out, indexed like the voices that replaced them
+    stolen_voices: [Option<Voice>; MAX_VOICES],
+    next_voice_id: u64,
+    declick_frames: u32,
+    // one frame of the output
+    mix: Vec<f64>,
+    // position within the bar, shared by both pulse streams
+    clock: Clock,
+    next_beat: u32,
+    // pulse index within the next beat
+    next_pulse: u32,
+    // pulse index of the polyrhythm stream within the bar
+    next_poly_pulse: u32,
+    // number of count-in bars left before the bars are counted
+    count_in: u32,
+    // number of bars since the end of the count-in
+    bar: u64,
+    // number of bars since the last trainer step
+    trainer_bar: u32,
+    // whether the current bar is silenced by the gap mode
+    bar_muted: bool,
+    rng: fastrand::Rng,
     was_playing: bool,
 }
 
 impl Sampler {
-    pub fn new(param: Arc<SamplerParam>, sender: Option<Sender<SamplerEvent>>) -> Result<Self> {
-        // It must success
-        let mut reader = hound::WavReader::new(AUDIO_FILE).unwrap();
-        Self::from_reader(&mut reader, param, sender)
-    }
-
-    #[allow(dead_code)]
-    pub fn from_path(
-        file_path: &str,
+    pub fn new(
+        sounds: Sounds,
         param: Arc<SamplerParam>,
         sender: Option<Sender<SamplerEvent>>,
-    ) -> Result<Self> {
-        let mut reader =
-            WavReader::new(BufReader::new(std::fs::File::open(file_path).unwrap())).unwrap();
-        Self::from_reader(&mut reader, param, sender)
+    ) -> Self {
+        let count_in = param.count_in_bars.load(Ordering::Relaxed);
+        Self {
+            sounds,
+            param,
+            sender,
+            commands: None,
+            replaced_sounds: None,
+            voices: [None; MAX_VOICES],
+            stolen_voices: [None; MAX_VOICES],
+            next_voice_id: 0,
+            declick_frames: 0,
+            mix: Vec::new(),
+            // the sample rate is only known once the stream writes
+            clock: Clock::new(1),
+            next_beat: 0,
+            next_pulse: 0,
+            next_poly_pulse: 0,
+            count_in,
+            bar: 0,
+            trainer_bar: 0,
+            bar_muted: false,
+            rng: fastrand::Rng::new(),
+            was_playing: false,
+        }
     }
 
-    pub fn from_reader<R: io::Read>(
-        reader: &mut WavReader<R>,
-        param: Arc<SamplerParam>,
-        sender: Option<Sender<SamplerEvent>>,
-    ) -> Result<Self> {
-        let spec = reader.spec();
-        let bit_depth = spec.bits_per_sample;
-
-        match spec.sample_format {
-            SampleFormat::Float => {
-                let buffer_in = read_samples_to_buffer::<f32, _>(reader);
-                let mut buffer_out = vec![0.0; buffer_in.len()];
-                buffer_f32_to_f64(&buffer_in, &mut buffer_out)?;
-                Ok(Self {
-                    samples: buffer_out,
-                    n_channels: spec.channels,
-                    sample_rate: spec.sample_rate,
-                    playhead: 0.0,
-                    param,
-                    sender,
-                    was_playing: false,
-                })
+    /// Resamples all the samples to the sample rate of the output stream.
+    ///
+    /// This should be called before the stream starts.
+    pub fn prepare(&mut self, sample_rate: u32) {
+        self.sounds.prepare(sample_rate);
+    }
+
+    /// Connects a command queue, the commands are applied at the start of
+    /// every `write`.
+    pub fn command_queue(&mut self) -> CommandSender {
+        let (sender, receiver) = command_queue();
+        self.commands = Some(receiver);
+        sender
+    }
+
+    /// Sends an event to the UI, a receiver that is gone is ignored since
+    /// the audio thread must not panic while the output shuts down.
+    pub fn send_event(&self, event: SamplerEvent) {
+        if let Some(ref _sender) = self.sender {
+            let _ = _sender.send(event);
+        }
+    }
+
+    /// Applies the pending commands and sends them back for deallocation.
+    ///
+    /// The commands wait while voices are fading out on replaced sounds.
+    fn apply_commands(&mut self) {
+        let Some(mut commands) = self.commands.take() else {
+            return;
+        };
+        if self.replaced_sounds.is_some() {
+            let fading = self
+                .voices
+                .iter()
+                .chain(self.stolen_voices.iter())
+                .flatten()
+                .any(|x| x.replaced);
+            if fading {
+                self.commands = Some(commands);
+                return;
             }
-            SampleFormat::Int => {
-                let samples: Vec<f64> = match bit_depth {
-                    16 => {
-                        let buffer_in = read_samples_to_buffer::<i16, _>(reader);
-                        let mut buffer_out = vec![0.0; buffer_in.len()];
-                        buffer_i16_to_f64(&buffer_in, bit_depth, &mut buffer_out)?;
-                        Ok(buffer_out)
-                    }
-                    24 | 32 => {
-                        let buffer_in = read_samples_to_buffer::<i32, _>(reader);
-                        let mut buffer_out = vec![0.0; buffer_in.len()];
-                        buffer_i32_to_f64(&buffer_in, bit_depth, &mut buffer_out)?;
-                        Ok(buffer_out)
+            // `pop` made room for it and nothing was sent back since
+            commands.recycle(self.replaced_sounds.take().unwrap());
+        }
+        while let Some(mut command) = commands.pop() {
+            match command {
+                SamplerCommand::Sounds(ref mut sounds) => {
+                    // the old sounds go back with the command, once the
+                    // voices playing them have faded out
+                    std::mem::swap(&mut self.sounds, sounds);
+                    let frames = self.declick_frames;
+                    for voice in self.voices.iter_mut().chain(self.stolen_voices.iter_mut()) {
+                        *voice = voice.map(|x| x.fade_out(frames).replaced());
                     }
-                    _ => Err(eyre!("Unsupported integer sample format bit depth")),
-                }?;
-
-                Ok(Self {
-                    samples,
-                    n_channels: spec.channels,
-                    sample_rate: spec.sample_rate,
-                    playhead: 0.0,
-                    param,
-                    sender,
-                    was_playing: false,
-                })
+                    self.replaced_sounds = Some(command);
+                    break;
+                }
+                SamplerCommand::Pattern {
+                    ref pattern,
+                    total_beats,
+                } => {
+                    self.param.pattern.store_all(pattern, Ordering::Relaxed);
+                    self.param.total_beats.store(total_beats, Ordering::Relaxed);
+                }
+                SamplerCommand::Restart => self.reset(),
+                SamplerCommand::Tempo(bpm) => {
+                    self.param.pending_bpm.store(bpm, Ordering::Relaxed);
+                }
             }
+            commands.recycle(command);
         }
+        self.commands = Some(commands);
     }
 
-    pub fn send_tick(&self) -> Result<()> {
-        if let Some(ref _sender) = self.sender {
-            _sender.send(SamplerEvent::Tick)?;
+    /// Applies the pending tempo, if any.
+    fn apply_pending_tempo(&mut self) {
+        let bpm = self.param.pending_bpm.swap(0.0, Ordering::Relaxed);
+        if bpm > 0.0 {
+            self.param.bpm.store(bpm, Ordering::Relaxed);
         }
-        Ok(())
     }
 
-    fn cycle_length(&self) -> f64 {
+    /// Rewinds the clock to the count-in, the ringing voices are faded out.
+    fn reset(&mut self) {
+        let frames = self.declick_frames;
+        for voice in self.voices.iter_mut().chain(self.stolen_voices.iter_mut()) {
+            *voice = voice.map(|x| x.fade_out(frames));
+        }
+        self.clock.reset();
+        self.next_beat = 0;
+        self.next_pulse = 0;
+        self.next_poly_pulse = 0;
+        self.count_in = self.param.count_in_bars.load(Ordering::Relaxed);
+        self.bar = 0;
+        self.trainer_bar = 0;
+        self.bar_muted = false;
+    }
+
+    /// Wraps the position once it reaches the end of the bar.
+    fn update_bar(&mut self) {
+        let total_beats = self.param.total_beats.load(Ordering::Relaxed).max(1);
+        if self.clock.reached(total_beats as u64, 1) {
+            // keep the phase within the beat, also handles a shrunk bar
+            self.clock.wrap();
+            self.next_beat = 0;
+            self.next_pulse = 0;
+            self.next_poly_pulse = 0;
+            if self.param.tempo_change.load(Ordering::Relaxed) == TempoChange::Bar {
+                self.apply_pending_tempo();
+            }
+            // the first bar after the count-in is bar 0
+            if self.count_in > 0 {
+                self.count_in -= 1;
+                return;
+            }
+            self.bar += 1;
+            self.update_trainer();
+            self.bar_muted = self.decide_bar_muted();
+        }
+    }
+
+    /// Decides whether the bar that has just started is silent.
+    fn decide_bar_muted(&mut self) -> bool {
+        match self.param.gap_mode.load(Ordering::Relaxed) {
+            GapMode::Off => false,
+            GapMode::Gap => {
+                let play_bars = self.param.gap_play_bars.load(Ordering::Relaxed) as u64;
+                let mute_bars = self.param.gap_mute_bars.load(Ordering::Relaxed) as u64;
+                play_bars + mute_bars > 0 && self.bar % (play_bars + mute_bars) >= play_bars
+            }
+            GapMode::Random => {
+                let probability = self.param.mute_probability.load(Ordering::Relaxed);
+                self.rng.f64() < probability
+            }
+        }
+    }
+
+    /// Moves the BPM towards the trainer target every `trainer_bars` bars.
+    ///
+    /// It is only called at the bar boundary, so a step never lands mid-bar.
+    fn update_trainer(&mut self) {
+        if !self.param.trainer.load(Ordering::Relaxed) {
+            self.trainer_bar = 0;
+            return;
+        }
+
+        self.trainer_bar += 1;
+        if self.trainer_bar < self.param.trainer_bars.load(Ordering::Relaxed).max(1) {
+            return;
+        }
+        self.trainer_bar = 0;
+
         let bpm = self.param.bpm.load(Ordering::Relaxed);
-        self.sample_rate as f64 * 60.0 / bpm
+        let step = self.param.trainer_step.load(Ordering::Relaxed).abs();
+        let target = self.param.trainer_target.load(Ordering::Relaxed).clamp(
+            self.param.min_bpm.load(Ordering::Relaxed),
+            self.param.max_bpm.load(Ordering::Relaxed),
+        );
+        let new_bpm = if bpm < target {
+            inc_by_precision(bpm, step, BPM_PRECISION).min(target)
+        } else {
+            inc_by_precision(bpm, -step, BPM_PRECISION).max(target)
+        };
+        if new_bpm != bpm {
+            self.param.bpm.store(new_bpm, Ordering::Relaxed);
+            self.send_event(SamplerEvent::TrainerStep);
+        }
+    }
+
+    /// Triggers the next beat or subdivision pulse once the position reaches it.
+    fn update_main_pulse(&mut self) {
+        let total_beats = self.param.total_beats.load(Ordering::Relaxed).max(1);
+        let subdivision = self.param.subdivision.load(Ordering::Relaxed).max(1);
+        if self.next_pulse >= subdivision {
+            // the subdivision has been reduced within the beat
+            self.next_beat += 1;
+            self.next_pulse = 0;
+        }
+
+        let swing = self.param.swing.load(Ordering::Relaxed);
+        let (offset, den) = pulse_offset(self.next_pulse, subdivision, swing);
+        let pulse_position = self.next_beat as u64 * den + offset;
+        if self.next_beat >= total_beats || !self.clock.reached(pulse_position, den) {
+            return;
+        }
+        if self.next_pulse == 0
+            && self.param.tempo_change.load(Ordering::Relaxed) == TempoChange::Beat
+        {
+            self.apply_pending_tempo();
+        }
+
+        let counting_in = self.count_in > 0;
+        let click = if counting_in {
+            // every beat is counted, without the subdivisions
+            (self.next_pulse == 0).then_some(Click::CountIn)
+        } else if self.next_pulse == 0 {
+            match self.param.pattern.load(self.next_beat, Ordering::Relaxed) {
+                BeatKind::Accent => Some(Click::Accent),
+                BeatKind::Normal => Some(Click::Normal),
+                BeatKind::Ghost => Some(Click::Ghost),
+                BeatKind::Mute => None,
+            }
+        } else {
+            Some(Click::Subdivision)
+        };
+        if let (Some(click), false) = (click, self.bar_muted) {
+            self.trigger(click, Stream::Main);
+        }
+        if self.next_pulse == 0 {
+            self.send_event(SamplerEvent::Beat {
+                beat: self.next_beat,
+                bar: self.bar,
+                muted: self.bar_muted,
+                count_in: counting_in,
+            });
+        }
+
+        self.next_pulse += 1;
+        if self.next_pulse == subdivision {
+            self.next_beat += 1;
+            self.next_pulse = 0;
+        }
+    }
+
+    /// Triggers the next polyrhythm pulse, which evenly divides the same bar.
+    fn update_poly_pulse(&mut self) {
+        let total_beats = self.param.total_beats.load(Ordering::Relaxed).max(1);
+        let poly_beats = self.param.poly_beats.load(Ordering::Relaxed).max(1);
+
+        // the latest pulse that is due, skipping the ones missed by a change of `poly_beats`
+        let due = self.clock.divisions(poly_beats as u64, total_beats as u64) as u32;
+        if self.next_poly_pulse >= poly_beats || due < self.next_poly_pulse {
+            return;
+        }
+
+        // the stream keeps counting while disabled so that it can be enabled mid-bar
+        if self.param.polyrhythm.load(Ordering::Relaxed) && !self.bar_muted && self.count_in == 0 {
+            self.trigger(Click::Poly, Stream::Poly);
+            self.send_event(SamplerEvent::PolyPulse { pulse: due });
+        }
+        self.next_poly_pulse = due + 1;
+    }
+
+    /// Starts a voice, stealing the oldest one if the pool is full.
+    fn trigger(&mut self, click: Click, stream: Stream) {
+        let voice = Voice {
+            click,
+            stream,
+            playhead: 0.0,
+            id: self.next_voice_id,
+            fade: None,
+            replaced: false,
+        };
+        self.next_voice_id += 1;
+
+        let idx = match self.voices.iter().position(|x| x.is_none()) {
+            Some(idx) => idx,
+            None => {
+                let idx = (0..MAX_VOICES)
+                    .min_by_key(|&i| self.voices[i].map_or(0, |x| x.id))
+                    .unwrap_or(0);
+                // the stolen voice keeps ringing in the background until faded
+                let frames = self.declick_frames;
+                self.stolen_voices[idx] = self.voices[idx].map(|x| x.fade_out(frames));
+                idx
+            }
+        };
+        self.voices[idx] = Some(voice);
+    }
+
+    /// The sample, gain and playback rate of a click among the given sounds
+    fn click_source<'a>(&'a self, sounds: &'a Sounds, click: Click) -> (&'a Sample, f64, f64) {
+        match click {
+            Click::Normal => (&sounds.normal, 1.0, 1.0),
+            Click::Accent => match sounds.accent {
+                Some(ref sample) => (sample, self.param.accent_gain.load(Ordering::Relaxed), 1.0),
+                None => (
+                    &sounds.normal,
+                    self.param.accent_gain.load(Ordering::Relaxed),
+                    self.param.accent_pitch.load(Ordering::Relaxed),
+                ),
+            },
+            Click::Ghost => (
+                &sounds.normal,
+                self.param.ghost_gain.load(Ordering::Relaxed),
+                1.0,
+            ),
+            Click::Subdivision => (
+                sounds.subdivision.as_ref().unwrap_or(&sounds.normal),
+                self.param.subdivision_gain.load(Ordering::Relaxed),
+                1.0,
+            ),
+            Click::Poly => match sounds.poly {
+                Some(ref sample) => (sample, 1.0, 1.0),
+                None => (&sounds.normal, 1.0, POLY_PITCH),
+            },
+            Click::CountIn => match sounds.count_in {
+                Some(ref sample) => (sample, 1.0, 1.0),
+                None => (&sounds.normal, 1.0, COUNT_IN_PITCH),
+            },
+        }
+    }
+
+    /// Adds the current frame of the voice to `out` and moves its playhead.
+    ///
+    /// Returns `false` once the voice has reached the end of its sample or
+    /// of its fade-out.
+    fn render_voice(&self, voice: &mut Voice, sample_rate: u32, out: &mut [f64]) -> bool {
+        let sounds = match (voice.replaced, &self.replaced_sounds) {
+            (true, Some(SamplerCommand::Sounds(sounds))) => sounds,
+            _ => &self.sounds,
+        };
+        let (sample, click_gain, pitch) = self.click_source(sounds, voice.click);
+        let mut gain = match voice.stream {
+            Stream::Main => self.param.main_gain.load(Ordering::Relaxed),
+            Stream::Poly => self.param.poly_gain.load(Ordering::Relaxed),
+        };
+        if let Some(fade) = voice.fade {
+            if fade == 0 {
+                return false;
+            }
+            gain *= fade as f64 / self.declick_frames.max(1) as f64;
+            voice.fade = Some(fade - 1);
+        }
+
+        let idx = voice.playhead.floor() as usize;
+        let frac = voice.playhead - idx as f64;
+        match sample.frame(idx) {
+            Some(frame) => {
+                // linear interpolation, the playhead only has a fractional
+                // part with a pitch or an unprepared sample rate
+                let gain = click_gain * gain;
+                mix_frame(frame, gain * (1.0 - frac), out);
+                if let (Some(next_frame), true) = (sample.frame(idx + 1), frac > 0.0) {
+                    mix_frame(next_frame, gain * frac, out);
+                }
+                voice.playhead += sample.sample_rate() as f64 / sample_rate as f64 * pitch;
+                true
+            }
+            None => false,
+        }
     }
 
     pub fn write<T>(&mut self, data: &mut [T], sample_rate: u32, n_channels: u16)
     where
         T: SizedSample + FromSample<f64>,
     {
+        self.apply_commands();
+        // nothing to wait for when stopped
+        if self.param.tempo_change.load(Ordering::Relaxed) == TempoChange::Immediate
+            || !self.param.playing.load(Ordering::Relaxed)
+        {
+            self.apply_pending_tempo();
+        }
+        self.clock.set_sample_rate(sample_rate);
+        self.declick_frames = (DECLICK_SECONDS * sample_rate as f64) as u32;
         for frame in data.chunks_mut(n_channels as usize) {
             // update playing state
             let playing = self.param.playing.load(Ordering::Relaxed);
@@ -131,76 +769,236 @@ impl Sampler {
                 self.was_playing = true;
             } else if self.was_playing && !playing {
                 self.was_playing = false;
-                self.playhead = 0.0;
+                self.reset();
             }
 
-            // skip if is not playing
-            if !playing {
-                continue;
+            if playing {
+                self.update_bar();
+                self.update_main_pulse();
+                self.update_poly_pulse();
             }
 
-            // BUG: This does not handle stereo samples.
-            let volume = self.param.volume.load(Ordering::Relaxed);
-            let idx = self.playhead.round() as usize;
-            if idx < self.samples.len() {
-                let value: T = T::from_sample(self.samples[idx] * volume);
-                for sample in frame.iter_mut() {
-                    *sample = value;
+            // the buffers are copied out to be borrowed along with `self`
+            let mut mix = std::mem::take(&mut self.mix);
+            mix.resize(frame.len(), 0.0);
+            mix.fill(0.0);
+            let mut voices = self.voices;
+            let mut stolen_voices = self.stolen_voices;
+            for voice in voices.iter_mut().chain(stolen_voices.iter_mut()) {
+                if let Some(ref mut x) = voice {
+                    if !self.render_voice(x, sample_rate, &mut mix) {
+                        *voice = None;
+                    }
                 }
             }
+            self.voices = voices;
+            self.stolen_voices = stolen_voices;
 
-            let inc = self.sample_rate as f64 / sample_rate as f64;
-            let playhead_inc = self.playhead + inc;
-            let length = self.cycle_length();
-
-            // move the clock
-            if playhead_inc < length {
-                self.playhead = playhead_inc;
-            } else {
-                self.playhead = playhead_inc - length;
-                // send a tick whenever the playhead rewinds
-                self.send_tick().unwrap();
+            // silence is written as well, the buffer might hold garbage
+            let volume = self.param.volume.load(Ordering::Relaxed);
+            let pan = self.param.pan.load(Ordering::Relaxed);
+            apply_pan(pan, &mut mix);
+            for (sample, value) in frame.iter_mut().zip(mix.iter()) {
+                *sample = T::from_sample(value * volume);
+            }
+            self.mix = mix;
+
+            // the clock only moves while playing
+            if !playing {
+                continue;
             }
+            let bpm = self.param.bpm.load(Ordering::Relaxed);
+            self.clock.advance(1, bpm);
+        }
+    }
+}
+
+/// Adds a frame to a frame of a different channel count.
+///
+/// Mono is copied to every channel and missing channels are left untouched.
+/// The common layouts up to 7.1 are folded down to stereo with the usual
+/// matrix, the LFE channel is dropped, and to mono by averaging both sides.
+/// Extra channels of other layouts are averaged with the ones that share an
+/// output channel.
+fn mix_frame(input: &[f64], gain: f64, out: &mut [f64]) {
+    let n_in = input.len();
+    let n_out = out.len();
+    if n_in == 1 {
+        for value in out.iter_mut() {
+            *value += input[0] * gain;
+        }
+    } else if n_in <= n_out {
+        for (value_in, value_out) in input.iter().zip(out.iter_mut()) {
+            *value_out += value_in * gain;
+        }
+    } else if let Some(matrix) = stereo_downmix(n_in) {
+        let (left, right) = input.iter().zip(matrix).fold(
+            (0.0, 0.0),
+            |(left, right), (value, (to_left, to_right))| {
+                (left + value * to_left, right + value * to_right)
+            },
+        );
+        if n_out == 1 {
+            out[0] += (left + right) * 0.5 * gain;
+        } else {
+            out[0] += left * gain;
+            out[1] += right * gain;
+        }
+    } else {
+        for (channel, value) in input.iter().enumerate() {
+            let n_shared = (n_in - channel % n_out).div_ceil(n_out);
+            out[channel % n_out] += value * gain / n_shared as f64;
         }
     }
 }
 
-fn read_samples_to_buffer<T, R>(reader: &mut WavReader<R>) -> Vec<T>
-where
-    R: io::Read,
-    T: hound::Sample,
-{
-    reader.samples::<T>().map(|x| x.unwrap()).collect()
+/// Gains of every channel into the left and right channels, for the layouts
+/// of WAV files with 2 to 8 channels.
+fn stereo_downmix(n_channels: usize) -> Option<&'static [(f64, f64)]> {
+    // centre and surround channels are shared by both sides at -3 dB
+    const SIDE: f64 = std::f64::consts::FRAC_1_SQRT_2;
+    const L: (f64, f64) = (1.0, 0.0);
+    const R: (f64, f64) = (0.0, 1.0);
+    const C: (f64, f64) = (SIDE, SIDE);
+    const LFE: (f64, f64) = (0.0, 0.0);
+    const LS: (f64, f64) = (SIDE, 0.0);
+    const RS: (f64, f64) = (0.0, SIDE);
+    // a back centre goes to both surround channels at -3 dB
+    const BC: (f64, f64) = (0.5, 0.5);
+    match n_channels {
+        2 => Some(&[L, R]),
+        3 => Some(&[L, R, C]),
+        4 => Some(&[L, R, LS, RS]),
+        5 => Some(&[L, R, C, LS, RS]),
+        6 => Some(&[L, R, C, LFE, LS, RS]),
+        7 => Some(&[L, R, C, LFE, BC, LS, RS]),
+        8 => Some(&[L, R, C, LFE, LS, RS, LS, RS]),
+        _ => None,
+    }
 }
 
-fn buffer_i32_to_f64(buffer_in: &[i32], bit_depth: u16, buffer_out: &mut [f64]) -> Result<()> {
-    let max_value = match bit_depth {
-        24 => Ok(((1 << 23) - 1) as f64),
-        32 => Ok(i32::MAX as f64),
-        _ => Err(eyre!("Not supported bit depth")),
-    }?;
-    for (sample_in, sample_out) in buffer_in.iter().zip(buffer_out.iter_mut()) {
-        let normalized_sample = *sample_in as f64 / max_value;
-        *sample_out = normalized_sample;
+/// Balances the first two channels, `pan` ranges from -1 (left) to 1 (right).
+fn apply_pan(pan: f64, frame: &mut [f64]) {
+    if frame.len() < 2 {
+        return;
     }
-    Ok(())
+    frame[0] *= (1.0 - pan).min(1.0);
+    frame[1] *= (1.0 + pan).min(1.0);
 }
 
-fn buffer_i16_to_f64(buffer_in: &[i16], bit_depth: u16, buffer_out: &mut [f64]) -> Result<()> {
-    let max_value = match bit_depth {
-        16 => Ok(i16::MAX as f64),
-        _ => Err(eyre!("Not supported bit depth")),
-    }?;
-    for (sample_in, sample_out) in buffer_in.iter().zip(buffer_out.iter_mut()) {
-        let normalized_sample = *sample_in as f64 / max_value;
-        *sample_out = normalized_sample;
+/// Offset of a pulse within its beat, in beats as a fraction `(num, den)`.
+///
+/// Every second pulse of a complete pair is delayed according to `swing`,
+/// which is rounded to a thousandth so that the offset stays exact.
+fn pulse_offset(pulse: u32, subdivision: u32, swing: f64) -> (u64, u64) {
+    let den = subdivision as u64 * SWING_SCALE;
+    if pulse % 2 == 1 {
+        let swing = (swing * SWING_SCALE as f64).round() as u64;
+        ((pulse - 1) as u64 * SWING_SCALE + 2 * swing, den)
+    } else {
+        (pulse as u64 * SWING_SCALE, den)
     }
-    Ok(())
 }
 
-fn buffer_f32_to_f64(buffer_in: &[f32], buffer_out: &mut [f64]) -> Result<()> {
-    for (sample_in, sample_out) in buffer_in.iter().zip(buffer_out.iter_mut()) {
-        *sample_out = *sample_in as f64;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_FRAMES: usize = 512;
+
+    /// Renders with a one-frame click and returns the frames where clicks start.
+    fn onsets(param: SamplerParam, sample_rate: u32, seconds: u64) -> Vec<u64> {
+        let sounds = Sounds::from_sample(Sample::new(vec![1.0], 1, sample_rate));
+        let mut sampler = Sampler::new(sounds, Arc::new(param), None);
+        let mut data = [0.0f32; BLOCK_FRAMES];
+        let mut onsets = Vec::new();
+        let n_blocks = seconds * sample_rate as u64 / BLOCK_FRAMES as u64;
+        for block in 0..n_blocks {
+            sampler.write(&mut data, sample_rate, 1);
+            let start = block * BLOCK_FRAMES as u64;
+            onsets.extend(
+                (0..BLOCK_FRAMES)
+                    .filter(|&i| data[i] != 0.0)
+                    .map(|i| start + i as u64),
+            );
+        }
+        onsets
+    }
+
+    #[test]
+    fn beats_land_on_exact_frames() {
+        let sample_rate = 44100;
+        let mut config = CoryConfig::new(97.3, 1.0);
+        config.accent_pitch = 1.0;
+        let onsets = onsets(SamplerParam::from_config(&config), sample_rate, 60);
+
+        assert!(onsets.len() > 90);
+        for (beat, frame) in onsets.iter().enumerate() {
+            // the first frame at or after 60 / 97.3 seconds per beat
+            let expected = (beat as u64 * 60 * 1000 * sample_rate as u64).div_ceil(97300);
+            assert_eq!(*frame, expected, "beat {}", beat);
+        }
+    }
+
+    #[test]
+    fn replaced_sounds_fade_out() {
+        let sample_rate = 48000;
+        let long = Sounds::from_sample(Sample::new(vec![1.0; 48000], 1, sample_rate));
+        let short = Sounds::from_sample(Sample::new(vec![1.0; 10], 1, sample_rate));
+        let mut config = CoryConfig::new(120.0, 1.0);
+        config.accent_pitch = 1.0;
+        let mut sampler = Sampler::new(long, Arc::new(SamplerParam::from_config(&config)), None);
+        let mut commands = sampler.command_queue();
+        let mut data = [0.0f32; BLOCK_FRAMES];
+        sampler.write(&mut data, sample_rate, 1);
+
+        // the ringing click is past the end of the new sample
+        commands
+            .send(SamplerCommand::Sounds(Box::new(short)))
+            .unwrap();
+        sampler.write(&mut data, sample_rate, 1);
+        let declick_frames = (DECLICK_SECONDS * sample_rate as f64) as usize;
+        assert_eq!(data[0], 1.0);
+        for i in 1..declick_frames {
+            assert!(data[i] < data[i - 1] && data[i] > 0.0, "frame {}", i);
+        }
+        assert!(data[declick_frames..].iter().all(|&x| x == 0.0));
+
+        // the replaced sounds are sent back once faded out
+        sampler.write(&mut data, sample_rate, 1);
+        assert!(sampler.replaced_sounds.is_none());
+    }
+
+    #[test]
+    fn surround_folds_down_to_stereo_and_mono() {
+        // 5.1 in the WAV order: L R C LFE Ls Rs
+        let input = [1.0, 0.0, 1.0, 1.0, 0.0, 1.0];
+        let side = std::f64::consts::FRAC_1_SQRT_2;
+
+        let mut stereo = [0.0; 2];
+        mix_frame(&input, 1.0, &mut stereo);
+        assert!((stereo[0] - (1.0 + side)).abs() < 1e-12);
+        assert!((stereo[1] - 2.0 * side).abs() < 1e-12);
+
+        let mut mono = [0.0; 1];
+        mix_frame(&input, 1.0, &mut mono);
+        assert!((mono[0] - (1.0 + 3.0 * side) / 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn swung_pulses_land_on_exact_frames() {
+        let sample_rate = 48000;
+        let mut config = CoryConfig::new(90.0, 1.0);
+        config.subdivision = 2;
+        config.swing = 0.66;
+        let onsets = onsets(SamplerParam::from_config(&config), sample_rate, 60);
+
+        // a beat is 32000 frames, the swung pulse lands 0.66 of a beat later
+        assert_eq!(onsets.len(), 180);
+        for (pulse, frame) in onsets.iter().enumerate() {
+            let beat = pulse as u64 / 2;
+            let offset = if pulse % 2 == 1 { 21120 } else { 0 };
+            assert_eq!(*frame, beat * 32000 + offset, "pulse {}", pulse);
+        }
     }
-    Ok(())
 }
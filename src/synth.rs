@@ -0,0 +1,186 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::sample::Sample;
+
+// the synthesized clicks are resampled to the stream rate like any sample
+const SYNTH_SAMPLE_RATE: u32 = 48000;
+// the envelope is cut once it has decayed by about 60 dB
+const DECAY_LENGTH: f64 = 7.0;
+// a short attack avoids a click at the start of sine-like waveforms
+const ATTACK_SECONDS: f64 = 0.0005;
+const PEAK: f64 = 0.9;
+// frequency ratios of the upper partials, loosely after physical models
+const WOODBLOCK_RATIO: f64 = 2.76;
+const COWBELL_RATIO: f64 = 1.48;
+// noise is seeded so the same parameters always give the same sound
+const NOISE_SEED: u64 = 0x5eed;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Waveform {
+    Sine,
+    Square,
+    /// Noise through a band-pass filter centred on the pitch
+    Noise,
+    Woodblock,
+    Cowbell,
+}
+
+/// A synthesized click
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SynthParam {
+    pub waveform: Waveform,
+    /// Fundamental frequency in Hz
+    pub pitch: f64,
+    /// Time constant of the exponential decay in seconds
+    pub decay: f64,
+    /// Brightness from 0 (dull) to 1 (bright)
+    pub tone: f64,
+}
+
+impl Default for SynthParam {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::Woodblock,
+            pitch: 800.0,
+            decay: 0.03,
+            tone: 0.5,
+        }
+    }
+}
+
+impl SynthParam {
+    pub fn new(waveform: Waveform, pitch: f64, decay: f64, tone: f64) -> Self {
+        Self {
+            waveform,
+            pitch,
+            decay,
+            tone,
+        }
+    }
+
+    /// Renders the click into a mono sample.
+    pub fn render(&self) -> Sample {
+        let sample_rate = SYNTH_SAMPLE_RATE as f64;
+        let pitch = self.pitch.clamp(20.0, sample_rate / 4.0);
+        let decay = self.decay.max(0.001);
+        let tone = self.tone.clamp(0.0, 1.0);
+        let n_frames = (decay * DECAY_LENGTH * sample_rate) as usize;
+
+        let mut rng = fastrand::Rng::with_seed(NOISE_SEED);
+        let mut filter = OnePole::new(tone);
+        let mut band_pass = BandPass::new(pitch, tone, sample_rate);
+
+        let mut samples: Vec<f64> = (0..n_frames)
+            .map(|idx| {
+                let t = idx as f64 / sample_rate;
+                let phase = 2.0 * PI * pitch * t;
+                let value = match self.waveform {
+                    Waveform::Sine => phase.sin() + 0.5 * tone * (2.0 * phase).sin(),
+                    Waveform::Square => filter.process(square(phase)),
+                    Waveform::Noise => band_pass.process(rng.f64() * 2.0 - 1.0),
+                    Waveform::Woodblock => {
+                        // the upper mode dies out faster than the body
+                        let upper = (WOODBLOCK_RATIO * phase).sin() * (-t / (decay * 0.3)).exp();
+                        phase.sin() + tone * upper
+                    }
+                    Waveform::Cowbell => {
+                        filter.process(square(phase) + square(COWBELL_RATIO * phase))
+                    }
+                };
+                value * envelope(t, decay)
+            })
+            .collect();
+
+        let peak = samples.iter().fold(0.0, |acc: f64, x| acc.max(x.abs()));
+        if peak > 0.0 {
+            for value in samples.iter_mut() {
+                *value *= PEAK / peak;
+            }
+        }
+        Sample::new(samples, 1, SYNTH_SAMPLE_RATE)
+    }
+}
+
+/// The synthesized sounds of each kind of click
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SynthKit {
+    pub accent: SynthParam,
+    pub normal: SynthParam,
+    pub subdivision: SynthParam,
+    pub poly: SynthParam,
+}
+
+impl Default for SynthKit {
+    fn default() -> Self {
+        Self {
+            accent: SynthParam::new(Waveform::Woodblock, 1200.0, 0.03, 0.6),
+            normal: SynthParam::new(Waveform::Woodblock, 800.0, 0.03, 0.5),
+            subdivision: SynthParam::new(Waveform::Sine, 1000.0, 0.015, 0.2),
+            poly: SynthParam::new(Waveform::Cowbell, 540.0, 0.05, 0.4),
+        }
+    }
+}
+
+fn square(phase: f64) -> f64 {
+    if phase.sin() >= 0.0 {
+        1.0
+    } else {
+        -1.0
+    }
+}
+
+fn envelope(t: f64, decay: f64) -> f64 {
+    let attack = (t / ATTACK_SECONDS).min(1.0);
+    attack * (-t / decay).exp()
+}
+
+/// A one-pole low-pass filter, `tone` 1 lets everything through
+struct OnePole {
+    coefficient: f64,
+    state: f64,
+}
+
+impl OnePole {
+    fn new(tone: f64) -> Self {
+        Self {
+            coefficient: 0.05 + 0.95 * tone,
+            state: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        self.state += self.coefficient * (input - self.state);
+        self.state
+    }
+}
+
+/// A state variable band-pass filter, `tone` sets how wide the band is
+struct BandPass {
+    f: f64,
+    damping: f64,
+    low: f64,
+    band: f64,
+}
+
+impl BandPass {
+    fn new(frequency: f64, tone: f64, sample_rate: f64) -> Self {
+        Self {
+            f: 2.0 * (PI * frequency / sample_rate).sin(),
+            damping: 0.1 + 1.4 * tone,
+            low: 0.0,
+            band: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        self.low += self.f * self.band;
+        let high = input - self.low - self.damping * self.band;
+        self.band += self.f * high;
+        self.band
+    }
+}
@@ -14,30 +14,80 @@ use crossterm::{
 use eyre::Result;
 use ratatui::{
     layout::{Constraint, Direction, Layout},
-    prelude::{Alignment, Frame, Text},
-    style::{Color, Style},
+    prelude::{Alignment, Frame, Line, Span, Text},
+    style::{Color, Modifier, Style},
     widgets::{Block, Borders, Gauge, Paragraph},
 };
 
-use crate::config::{MAX_BPM, MAX_TOTAL_BEATS, MAX_VOLUME, MIN_BPM, MIN_TOTAL_BEATS, MIN_VOLUME};
-use crate::sampler::{SamplerEvent, SamplerParam};
+use crate::command::{CommandSender, SamplerCommand};
+use crate::config::{
+    BPM_PRECISION, BPM_STEPS, MAX_COUNT_IN_BARS, MAX_PAN, MAX_POLY_BEATS, MAX_SUBDIVISION,
+    MAX_SWING, MAX_TOTAL_BEATS, MAX_VOICE_GAIN, MAX_VOLUME, MIN_COUNT_IN_BARS, MIN_PAN,
+    MIN_POLY_BEATS, MIN_SUBDIVISION, MIN_SWING, MIN_TOTAL_BEATS, MIN_VOICE_GAIN, MIN_VOLUME,
+};
+use crate::pattern::Pattern;
+use crate::sampler::{GapMode, SamplerEvent, SamplerParam, TempoChange};
+use crate::utils::{inc_by_precision, precision_of, round_by_precision, AtomicF64};
 
 pub type CrosstermTerminal = ratatui::Terminal<ratatui::backend::CrosstermBackend<std::io::Stderr>>;
 
 #[derive(Debug)]
 pub struct App {
     pub param: Arc<SamplerParam>,
-    pub beat_count: u32,
+    /// BPM added or removed by the arrow keys
+    pub bpm_step: f64,
+    /// Changes that the audio thread applies at the start of a block
+    pub commands: CommandSender,
+    /// The BPM last asked for, the audio thread may not have applied it yet
+    pub bpm: f64,
+    /// The pattern being edited, sent whole on every change
+    pub pattern: Pattern,
+    /// The number of beats per bar being edited, sent along with the pattern
     pub total_beats: u32,
+    pub beat_count: u32,
+    /// The beat being edited in the pattern editor, starts from 0
+    pub pattern_cursor: u32,
+    /// The current polyrhythm pulse, starts from 1, 0 if none has been played
+    pub poly_count: u32,
+    /// Number of bars since the start, starts from 1
+    pub bar_count: u64,
+    /// Number of tempo changes made by the trainer since it was enabled
+    pub trainer_steps: u32,
+    /// Whether the current bar is silenced by the gap mode
+    pub bar_muted: bool,
+    /// Whether the current bar is a count-in bar
+    pub count_in: bool,
+    /// A problem worth showing, e.g. a sample that failed to load
+    pub warning: Option<String>,
+    /// Names of the available sound kits
+    pub kits: Vec<String>,
+    /// The selected sound kit, `None` for the default sounds
+    pub kit: Option<String>,
     pub should_quit: bool,
 }
 
 impl App {
-    pub fn new(param: Arc<SamplerParam>) -> Self {
+    pub fn new(param: Arc<SamplerParam>, commands: CommandSender) -> Self {
+        let bpm = param.bpm.load(Ordering::Relaxed);
+        let pattern = param.pattern.to_array(Ordering::Relaxed);
+        let total_beats = param.total_beats.load(Ordering::Relaxed);
         Self {
             param,
+            bpm_step: 1.0,
+            commands,
+            bpm,
+            pattern,
+            total_beats,
             beat_count: 1,
-            total_beats: 4,
+            pattern_cursor: 0,
+            poly_count: 0,
+            bar_count: 1,
+            trainer_steps: 0,
+            bar_muted: false,
+            count_in: false,
+            warning: None,
+            kits: Vec::new(),
+            kit: None,
             should_quit: false,
         }
     }
@@ -53,32 +103,144 @@ impl App {
         match ui_event {
             Action::Tick => {
                 // force the UI to refresh
+                self.commands.collect();
             }
             Action::Quit => {
                 self.should_quit = true;
             }
-            Action::IncBPM => {
-                let bpm = self.param.bpm.load(Ordering::Relaxed);
+            Action::TogglePlaying => {
+                let playing = self.param.playing.load(Ordering::Relaxed);
+                self.param.playing.store(!playing, Ordering::Relaxed);
+                if playing {
+                    self.reset_counts();
+                }
+            }
+            Action::CycleCountIn => {
+                let count_in_bars = self.param.count_in_bars.load(Ordering::Relaxed);
+                let count_in_bars = if count_in_bars < MAX_COUNT_IN_BARS {
+                    count_in_bars + 1
+                } else {
+                    MIN_COUNT_IN_BARS
+                };
                 self.param
-                    .bpm
-                    .store((bpm + 1.0).clamp(MIN_BPM, MAX_BPM), Ordering::Relaxed);
+                    .count_in_bars
+                    .store(count_in_bars, Ordering::Relaxed);
+            }
+            Action::RestartBar => {
+                self.send(SamplerCommand::Restart);
+                self.reset_counts();
             }
-            Action::DecBPM => {
-                let bpm = self.param.bpm.load(Ordering::Relaxed);
+            Action::IncBPM => self.add_bpm(self.bpm_step),
+            Action::DecBPM => self.add_bpm(-self.bpm_step),
+            Action::CycleBPMStep => {
+                // from coarse to fine, then back to coarse
+                let idx = BPM_STEPS.iter().position(|&x| x == self.bpm_step);
+                let idx = idx.map_or(0, |i| (i + 1) % BPM_STEPS.len());
+                self.bpm_step = BPM_STEPS[idx];
+            }
+            Action::CycleTempoChange => {
+                let tempo_change = self.param.tempo_change.load(Ordering::Relaxed);
                 self.param
-                    .bpm
-                    .store((bpm - 1.0).clamp(MIN_BPM, MAX_BPM), Ordering::Relaxed);
+                    .tempo_change
+                    .store(tempo_change.next(), Ordering::Relaxed);
             }
             Action::IncTotalBeats => {
                 if self.total_beats < MAX_TOTAL_BEATS {
                     self.total_beats += 1;
+                    self.send_pattern();
                 }
             }
             Action::DecTotalBeats => {
                 if self.total_beats > MIN_TOTAL_BEATS {
                     self.total_beats -= 1;
+                    self.send_pattern();
+                }
+            }
+            Action::IncSubdivision => {
+                let subdivision = self.param.subdivision.load(Ordering::Relaxed);
+                if subdivision < MAX_SUBDIVISION {
+                    self.param
+                        .subdivision
+                        .store(subdivision + 1, Ordering::Relaxed);
+                }
+            }
+            Action::DecSubdivision => {
+                let subdivision = self.param.subdivision.load(Ordering::Relaxed);
+                if subdivision > MIN_SUBDIVISION {
+                    self.param
+                        .subdivision
+                        .store(subdivision - 1, Ordering::Relaxed);
                 }
             }
+            Action::IncSwing => {
+                let swing = self.param.swing.load(Ordering::Relaxed);
+                self.param.swing.store(
+                    (swing + 0.01).clamp(MIN_SWING, MAX_SWING),
+                    Ordering::Relaxed,
+                );
+            }
+            Action::DecSwing => {
+                let swing = self.param.swing.load(Ordering::Relaxed);
+                self.param.swing.store(
+                    (swing - 0.01).clamp(MIN_SWING, MAX_SWING),
+                    Ordering::Relaxed,
+                );
+            }
+            Action::PrevPatternBeat => {
+                self.pattern_cursor = self.clamped_pattern_cursor().saturating_sub(1);
+            }
+            Action::NextPatternBeat => {
+                self.pattern_cursor = (self.clamped_pattern_cursor() + 1).min(self.total_beats - 1);
+            }
+            Action::CyclePatternBeat => {
+                let cursor = self.clamped_pattern_cursor() as usize;
+                self.pattern[cursor] = self.pattern[cursor].next();
+                self.send_pattern();
+            }
+            Action::TogglePolyrhythm => {
+                let polyrhythm = self.param.polyrhythm.load(Ordering::Relaxed);
+                self.param.polyrhythm.store(!polyrhythm, Ordering::Relaxed);
+                self.poly_count = 0;
+            }
+            Action::IncPolyBeats => {
+                let poly_beats = self.param.poly_beats.load(Ordering::Relaxed);
+                if poly_beats < MAX_POLY_BEATS {
+                    self.param
+                        .poly_beats
+                        .store(poly_beats + 1, Ordering::Relaxed);
+                }
+            }
+            Action::DecPolyBeats => {
+                let poly_beats = self.param.poly_beats.load(Ordering::Relaxed);
+                if poly_beats > MIN_POLY_BEATS {
+                    self.param
+                        .poly_beats
+                        .store(poly_beats - 1, Ordering::Relaxed);
+                }
+            }
+            Action::IncMainGain => add_voice_gain(&self.param.main_gain, 0.1),
+            Action::DecMainGain => add_voice_gain(&self.param.main_gain, -0.1),
+            Action::IncPolyGain => add_voice_gain(&self.param.poly_gain, 0.1),
+            Action::DecPolyGain => add_voice_gain(&self.param.poly_gain, -0.1),
+            Action::ToggleTrainer => {
+                let trainer = self.param.trainer.load(Ordering::Relaxed);
+                self.param.trainer.store(!trainer, Ordering::Relaxed);
+                self.trainer_steps = 0;
+            }
+            Action::CycleGapMode => {
+                let gap_mode = self.param.gap_mode.load(Ordering::Relaxed);
+                self.param
+                    .gap_mode
+                    .store(gap_mode.next(), Ordering::Relaxed);
+            }
+            Action::NextKit => {
+                // cycle through the kits, then back to the default sounds
+                let idx = match self.kit {
+                    Some(ref kit) => self.kits.iter().position(|x| x == kit).map(|i| i + 1),
+                    None => Some(0),
+                };
+                self.kit = idx.and_then(|i| self.kits.get(i)).cloned();
+            }
             Action::IncVolume => {
                 let volume = self.param.volume.load(Ordering::Relaxed);
                 self.param.volume.store(
@@ -93,23 +255,118 @@ impl App {
                     Ordering::Relaxed,
                 );
             }
+            Action::PanLeft => {
+                let pan = self.param.pan.load(Ordering::Relaxed);
+                self.param
+                    .pan
+                    .store((pan - 0.1).clamp(MIN_PAN, MAX_PAN), Ordering::Relaxed);
+            }
+            Action::PanRight => {
+                let pan = self.param.pan.load(Ordering::Relaxed);
+                self.param
+                    .pan
+                    .store((pan + 0.1).clamp(MIN_PAN, MAX_PAN), Ordering::Relaxed);
+            }
         };
     }
 
-    pub fn update_by_sampler_event(&mut self, _sampler_event: &SamplerEvent) {
-        // For now there is only one kind of event (tick), no need to parse
-        self.beat_count = self.beat_count % self.total_beats + 1;
+    fn add_bpm(&mut self, inc: f64) {
+        let min_bpm = self.param.min_bpm.load(Ordering::Relaxed);
+        let max_bpm = self.param.max_bpm.load(Ordering::Relaxed);
+        self.bpm = inc_by_precision(self.bpm, inc, BPM_PRECISION).clamp(min_bpm, max_bpm);
+        self.send(SamplerCommand::Tempo(self.bpm));
+    }
+
+    /// Goes back to the counts before the first beat, the sampler rewinds as well.
+    fn reset_counts(&mut self) {
+        self.beat_count = 1;
+        self.poly_count = 0;
+        self.bar_count = 1;
+        self.bar_muted = false;
+        self.count_in = false;
+    }
+
+    /// Sends a command to the audio thread, a full queue is shown as a warning.
+    pub fn send(&mut self, command: SamplerCommand) {
+        if let Err(e) = self.commands.send(command) {
+            self.warning = Some(e.to_string());
+        }
+    }
+
+    /// Sends the edited pattern along with the number of beats.
+    fn send_pattern(&mut self) {
+        self.send(SamplerCommand::Pattern {
+            pattern: self.pattern,
+            total_beats: self.total_beats,
+        });
+    }
+
+    /// The pattern cursor, kept within the current bar
+    pub fn clamped_pattern_cursor(&self) -> u32 {
+        self.pattern_cursor.min(self.total_beats.saturating_sub(1))
+    }
+
+    pub fn update_by_sampler_event(&mut self, sampler_event: &SamplerEvent) {
+        match sampler_event {
+            SamplerEvent::Beat {
+                beat,
+                bar,
+                muted,
+                count_in,
+            } => {
+                // the sampler counts from 0, the UI counts from 1
+                self.beat_count = beat + 1;
+                self.bar_count = bar + 1;
+                self.bar_muted = *muted;
+                self.count_in = *count_in;
+            }
+            SamplerEvent::PolyPulse { pulse } => {
+                self.poly_count = pulse + 1;
+            }
+            SamplerEvent::TrainerStep => {
+                self.trainer_steps += 1;
+                // the trainer changes the tempo by itself, unless a change
+                // asked for is still waiting for its beat or bar
+                if self.param.pending_bpm.load(Ordering::Relaxed) == 0.0 {
+                    self.bpm = self.param.bpm.load(Ordering::Relaxed);
+                }
+            }
+        }
     }
 }
 
 pub enum Action {
     Tick,
+    TogglePlaying,
+    RestartBar,
+    CycleCountIn,
     IncBPM,
     DecBPM,
+    CycleBPMStep,
+    CycleTempoChange,
     IncTotalBeats,
     DecTotalBeats,
+    IncSubdivision,
+    DecSubdivision,
+    IncSwing,
+    DecSwing,
+    PrevPatternBeat,
+    NextPatternBeat,
+    CyclePatternBeat,
+    TogglePolyrhythm,
+    IncPolyBeats,
+    DecPolyBeats,
+    IncMainGain,
+    DecMainGain,
+    IncPolyGain,
+    DecPolyGain,
+    ToggleTrainer,
+    CycleGapMode,
+    NextKit,
     IncVolume,
     DecVolume,
+    PanLeft,
+    PanRight,
     Quit,
 }
 
@@ -174,18 +431,41 @@ fn map_term_event(event: &CrosstermEvent) -> Option<Action> {
         CrosstermEvent::Key(e) => {
             if e.kind == event::KeyEventKind::Press {
                 match e.code {
+                    KeyCode::Char(' ') => Some(Action::TogglePlaying),
+                    KeyCode::Char('0') => Some(Action::RestartBar),
+                    KeyCode::Char('i') => Some(Action::CycleCountIn),
                     KeyCode::Right => Some(Action::IncBPM),
                     KeyCode::Left => Some(Action::DecBPM),
+                    KeyCode::Char('f') => Some(Action::CycleBPMStep),
                     KeyCode::Up => Some(Action::IncVolume),
                     KeyCode::Down => Some(Action::DecVolume),
+                    KeyCode::Char('z') => Some(Action::PanLeft),
+                    KeyCode::Char('x') => Some(Action::PanRight),
                     KeyCode::Char('k') => Some(Action::IncTotalBeats),
                     KeyCode::Char('j') => Some(Action::DecTotalBeats),
+                    KeyCode::Char('l') => Some(Action::IncSubdivision),
+                    KeyCode::Char('h') => Some(Action::DecSubdivision),
+                    KeyCode::Char('s') => Some(Action::IncSwing),
+                    KeyCode::Char('a') => Some(Action::DecSwing),
+                    KeyCode::Char(',') => Some(Action::PrevPatternBeat),
+                    KeyCode::Char('.') => Some(Action::NextPatternBeat),
+                    KeyCode::Char('p') => Some(Action::CyclePatternBeat),
+                    KeyCode::Char('r') => Some(Action::TogglePolyrhythm),
+                    KeyCode::Char(']') => Some(Action::IncPolyBeats),
+                    KeyCode::Char('[') => Some(Action::DecPolyBeats),
+                    KeyCode::Char('+') => Some(Action::IncMainGain),
+                    KeyCode::Char('_') => Some(Action::DecMainGain),
+                    KeyCode::Char('=') => Some(Action::IncPolyGain),
+                    KeyCode::Char('-') => Some(Action::DecPolyGain),
+                    KeyCode::Char('t') => Some(Action::ToggleTrainer),
+                    KeyCode::Char('m') => Some(Action::CycleGapMode),
+                    KeyCode::Char('n') => Some(Action::NextKit),
                     KeyCode::Esc | KeyCode::Char('q') => Some(Action::Quit),
                     KeyCode::Char('c') => {
                         if e.modifiers == KeyModifiers::CONTROL {
                             Some(Action::Quit)
                         } else {
-                            None
+                            Some(Action::CycleTempoChange)
                         }
                     }
                     _ => None, // ignore other key presses
@@ -253,7 +533,20 @@ impl Tui {
 
 pub fn render(app: &App, f: &mut Frame) {
     let bpm = app.param.bpm.load(Ordering::Relaxed);
+    let min_bpm = app.param.min_bpm.load(Ordering::Relaxed);
+    let max_bpm = app.param.max_bpm.load(Ordering::Relaxed);
+    let tempo_change = app.param.tempo_change.load(Ordering::Relaxed);
     let volume = app.param.volume.load(Ordering::Relaxed);
+    let pan = app.param.pan.load(Ordering::Relaxed);
+    let total_beats = app.total_beats;
+    let subdivision = app.param.subdivision.load(Ordering::Relaxed);
+    let swing = app.param.swing.load(Ordering::Relaxed);
+    let polyrhythm = app.param.polyrhythm.load(Ordering::Relaxed);
+    let poly_beats = app.param.poly_beats.load(Ordering::Relaxed);
+    let main_gain = app.param.main_gain.load(Ordering::Relaxed);
+    let poly_gain = app.param.poly_gain.load(Ordering::Relaxed);
+    let trainer = app.param.trainer.load(Ordering::Relaxed);
+    let gap_mode = app.param.gap_mode.load(Ordering::Relaxed);
 
     let chunks = Layout::default()
         .direction(Direction::Vertical)
@@ -262,11 +555,45 @@ pub fn render(app: &App, f: &mut Frame) {
             Constraint::Length(3),
             Constraint::Length(3),
             Constraint::Length(3),
+            Constraint::Length(4),
+            Constraint::Length(3),
+            Constraint::Length(3),
             Constraint::Length(3),
         ])
         .split(f.size());
 
-    let title = Paragraph::new(Text::styled("Cory Metronome", Style::default()))
+    let beat_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(chunks[2]);
+
+    let subdivision_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(chunks[3]);
+
+    let training_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(chunks[5]);
+
+    let output_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(chunks[6]);
+
+    let transport = if app.param.playing.load(Ordering::Relaxed) {
+        Span::styled("▶ Playing", Style::default().fg(Color::Green))
+    } else {
+        Span::styled("■ Stopped", Style::default().fg(Color::Red))
+    };
+    let count_in_bars = app.param.count_in_bars.load(Ordering::Relaxed);
+    let title_text = format!(
+        " (space, 0) - Count-in (i): {} - Cory Metronome - Kit (n): {}",
+        count_in_bars,
+        app.kit.as_deref().unwrap_or("default")
+    );
+    let title = Paragraph::new(Line::from(vec![transport, Span::raw(title_text)]))
         .alignment(Alignment::Center)
         .block(
             Block::default()
@@ -274,33 +601,274 @@ pub fn render(app: &App, f: &mut Frame) {
                 .style(Style::default()),
         );
 
+    let bpm_title = format!(
+        "BPM (←/→ by {}, f) - change {} (c)",
+        app.bpm_step,
+        tempo_change_name(tempo_change)
+    );
+    let show_bpm = |bpm| format_bpm(bpm, app.bpm_step);
+    let bpm_label = if app.bpm != bpm {
+        format!(
+            "{} → {}/{}",
+            show_bpm(bpm),
+            show_bpm(app.bpm),
+            show_bpm(max_bpm)
+        )
+    } else {
+        format!("{}/{}", show_bpm(bpm), show_bpm(max_bpm))
+    };
     let bpm_gauge = Gauge::default()
-        .block(Block::default().borders(Borders::ALL).title("BPM (←/→)"))
+        .block(Block::default().borders(Borders::ALL).title(bpm_title))
         .gauge_style(Style::default().fg(Color::White).bg(Color::Black))
-        .ratio(((bpm - MIN_BPM) / (MAX_BPM - MIN_BPM)).clamp(0.0, 1.0))
-        .label(format!("{}/{}", bpm, MAX_BPM));
-
+        .ratio(((bpm - min_bpm) / (max_bpm - min_bpm)).clamp(0.0, 1.0))
+        .label(bpm_label);
+
+    let (beat_label, beat_color) = if app.count_in {
+        (
+            format!("COUNT-IN {}/{}", app.beat_count, total_beats),
+            Color::Yellow,
+        )
+    } else {
+        (format!("{}/{}", app.beat_count, total_beats), Color::White)
+    };
     let beat_gauge = Gauge::default()
         .block(Block::default().borders(Borders::ALL).title("Beat (j/k)"))
+        .gauge_style(Style::default().fg(beat_color).bg(Color::Black))
+        .ratio((app.beat_count as f64 / total_beats as f64).clamp(0.0, 1.0))
+        .label(beat_label);
+
+    let swing_gauge = Gauge::default()
+        .block(Block::default().borders(Borders::ALL).title("Swing (a/s)"))
         .gauge_style(Style::default().fg(Color::White).bg(Color::Black))
-        .ratio((app.beat_count as f64 / app.total_beats as f64).clamp(0.0, 1.0))
-        .label(format!("{}/{}", app.beat_count, app.total_beats));
+        .ratio(((swing - MIN_SWING) / (MAX_SWING - MIN_SWING)).clamp(0.0, 1.0))
+        .label(format!("{:.0}%", swing * 100.0));
+
+    let cursor = app.clamped_pattern_cursor();
+    let pattern_spans: Vec<Span> = (0..total_beats)
+        .map(|i| {
+            let kind = app.pattern[i as usize];
+            let mut style = Style::default();
+            if i + 1 == app.beat_count {
+                style = style.fg(Color::Yellow).add_modifier(Modifier::BOLD);
+            }
+            if i == cursor {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+            Span::styled(format!(" {} ", kind.symbol()), style)
+        })
+        .collect();
+    let pattern = Paragraph::new(Line::from(pattern_spans))
+        .alignment(Alignment::Center)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Pattern (,/. p)"),
+        );
+
+    let subdivision_gauge = Gauge::default()
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Subdivision (h/l)"),
+        )
+        .gauge_style(Style::default().fg(Color::White).bg(Color::Black))
+        .ratio(
+            ((subdivision - MIN_SUBDIVISION) as f64 / (MAX_SUBDIVISION - MIN_SUBDIVISION) as f64)
+                .clamp(0.0, 1.0),
+        )
+        .label(subdivision_name(subdivision));
+
+    let poly_text = if polyrhythm {
+        Text::from(vec![
+            pulse_row(
+                format!("Main {:>3.0}%", main_gain * 100.0),
+                app.beat_count,
+                total_beats,
+            ),
+            pulse_row(
+                format!("Poly {:>3.0}%", poly_gain * 100.0),
+                app.poly_count,
+                poly_beats,
+            ),
+        ])
+    } else {
+        Text::from("Off (r)")
+    };
+    let poly =
+        Paragraph::new(poly_text).block(Block::default().borders(Borders::ALL).title(format!(
+            "Polyrhythm {}:{} (r, [/], _/+, -/=)",
+            total_beats, poly_beats
+        )));
+
+    let trainer_text = if trainer {
+        let step = app.param.trainer_step.load(Ordering::Relaxed);
+        let bars = app.param.trainer_bars.load(Ordering::Relaxed);
+        let target = app.param.trainer_target.load(Ordering::Relaxed);
+        let sign = if target < bpm { "-" } else { "+" };
+        format!(
+            "{}{} BPM every {} bars, target {}, step {} (bar {})",
+            sign, step, bars, target, app.trainer_steps, app.bar_count
+        )
+    } else {
+        "Off".to_string()
+    };
+    let trainer_paragraph = Paragraph::new(Text::from(trainer_text))
+        .block(Block::default().borders(Borders::ALL).title("Trainer (t)"));
+
+    let gap_text = match gap_mode {
+        GapMode::Off => "Off".to_string(),
+        GapMode::Gap => format!(
+            "Play {}, mute {} bars",
+            app.param.gap_play_bars.load(Ordering::Relaxed),
+            app.param.gap_mute_bars.load(Ordering::Relaxed)
+        ),
+        GapMode::Random => format!(
+            "Mute {:.0}% of bars",
+            app.param.mute_probability.load(Ordering::Relaxed) * 100.0
+        ),
+    };
+    let gap_text = match (gap_mode, app.bar_muted) {
+        (GapMode::Off, _) => gap_text,
+        (_, true) => format!("{} - bar {} silent", gap_text, app.bar_count),
+        (_, false) => format!("{} - bar {}", gap_text, app.bar_count),
+    };
+    let gap_paragraph = Paragraph::new(Text::from(gap_text)).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title("Gap Click (m)"),
+    );
 
     let volume_gauge = Gauge::default()
         .block(Block::default().borders(Borders::ALL).title("Volume (↑/↓)"))
         .gauge_style(Style::default().fg(Color::White).bg(Color::Black))
         .ratio(volume);
 
-    let desc = Paragraph::new(Text::styled(
-        "Press (q) or (Ctrl-C) to quit",
-        Style::default(),
-    ))
-    .alignment(Alignment::Left)
-    .block(Block::default().style(Style::default()));
+    // the pan is rounded to avoid showing "-0"
+    let pan_percent = (pan * 100.0).round() as i32;
+    let pan_gauge = Gauge::default()
+        .block(Block::default().borders(Borders::ALL).title("Pan (z/x)"))
+        .gauge_style(Style::default().fg(Color::White).bg(Color::Black))
+        .ratio(((pan - MIN_PAN) / (MAX_PAN - MIN_PAN)).clamp(0.0, 1.0))
+        .label(match pan_percent {
+            0 => "C".to_string(),
+            x if x < 0 => format!("L {}", -x),
+            x => format!("R {}", x),
+        });
+
+    let mut desc_lines = vec![Line::from("Press (q) or (Ctrl-C) to quit")];
+    if let Some(ref warning) = app.warning {
+        desc_lines.push(Line::styled(
+            format!("Warning: {}", warning),
+            Style::default().fg(Color::Yellow),
+        ));
+    }
+    let desc = Paragraph::new(Text::from(desc_lines))
+        .alignment(Alignment::Left)
+        .block(Block::default().style(Style::default()));
 
     f.render_widget(title, chunks[0]);
     f.render_widget(bpm_gauge, chunks[1]);
-    f.render_widget(beat_gauge, chunks[2]);
-    f.render_widget(volume_gauge, chunks[3]);
-    f.render_widget(desc, chunks[4]);
+    f.render_widget(beat_gauge, beat_chunks[0]);
+    f.render_widget(pattern, beat_chunks[1]);
+    f.render_widget(subdivision_gauge, subdivision_chunks[0]);
+    f.render_widget(swing_gauge, subdivision_chunks[1]);
+    f.render_widget(poly, chunks[4]);
+    f.render_widget(trainer_paragraph, training_chunks[0]);
+    f.render_widget(gap_paragraph, training_chunks[1]);
+    f.render_widget(volume_gauge, output_chunks[0]);
+    f.render_widget(pan_gauge, output_chunks[1]);
+    f.render_widget(desc, chunks[7]);
+}
+
+/// A row of pulses with the current one highlighted, `count` starts from 1
+fn pulse_row(label: String, count: u32, total: u32) -> Line<'static> {
+    let mut spans = vec![Span::raw(format!("{} ", label))];
+    spans.extend((1..=total).map(|i| {
+        if i == count {
+            Span::styled(" ● ", Style::default().fg(Color::Yellow))
+        } else {
+            Span::raw(" ○ ")
+        }
+    }));
+    Line::from(spans)
+}
+
+fn add_voice_gain(gain: &AtomicF64, inc: f64) {
+    let value = gain.load(Ordering::Relaxed);
+    gain.store(
+        (value + inc).clamp(MIN_VOICE_GAIN, MAX_VOICE_GAIN),
+        Ordering::Relaxed,
+    );
+}
+
+/// Shows a BPM with the decimals of the step, or all of them if it is not a
+/// multiple of the step.
+fn format_bpm(bpm: f64, step: f64) -> String {
+    let precision = precision_of(step);
+    let precision = if round_by_precision(bpm, precision) == bpm {
+        precision
+    } else {
+        BPM_PRECISION
+    };
+    format!("{:.*}", precision as usize, bpm)
+}
+
+fn tempo_change_name(tempo_change: TempoChange) -> &'static str {
+    match tempo_change {
+        TempoChange::Immediate => "immediately",
+        TempoChange::Beat => "at next beat",
+        TempoChange::Bar => "at next bar",
+    }
+}
+
+fn subdivision_name(subdivision: u32) -> String {
+    match subdivision {
+        1 => "None".to_string(),
+        2 => "Eighths".to_string(),
+        3 => "Triplets".to_string(),
+        4 => "Sixteenths".to_string(),
+        5 => "Quintuplets".to_string(),
+        6 => "Sextuplets".to_string(),
+        n => format!("{} per beat", n),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::command_queue;
+    use crate::config::CoryConfig;
+    use crate::pattern::BeatKind;
+
+    #[test]
+    fn edits_before_the_audio_thread_applies_them_add_up() {
+        let param = Arc::new(SamplerParam::from_config(&CoryConfig::default()));
+        let (sender, mut receiver) = command_queue();
+        let mut app = App::new(param, sender);
+        let bpm = app.bpm;
+
+        // no block is written in between, nothing gets applied
+        app.update_by_ui_event(&Action::CyclePatternBeat);
+        app.update_by_ui_event(&Action::CyclePatternBeat);
+        app.update_by_ui_event(&Action::IncTotalBeats);
+        app.update_by_ui_event(&Action::IncBPM);
+        app.update_by_ui_event(&Action::IncBPM);
+
+        let mut last_pattern = None;
+        let mut last_bpm = None;
+        while let Some(command) = receiver.pop() {
+            match command {
+                SamplerCommand::Pattern {
+                    pattern,
+                    total_beats,
+                } => last_pattern = Some((pattern, total_beats)),
+                SamplerCommand::Tempo(bpm) => last_bpm = Some(bpm),
+                _ => {}
+            }
+        }
+        let (pattern, total_beats) = last_pattern.unwrap();
+        assert_eq!(pattern[0], BeatKind::Ghost);
+        assert_eq!(total_beats, 5);
+        assert_eq!(last_bpm, Some(bpm + 2.0));
+    }
 }
@@ -20,6 +20,10 @@ impl AtomicF64 {
         let as_u64 = self.storage.load(ordering);
         f64::from_bits(as_u64)
     }
+    pub fn swap(&self, value: f64, ordering: Ordering) -> f64 {
+        let as_u64 = self.storage.swap(value.to_bits(), ordering);
+        f64::from_bits(as_u64)
+    }
 }
 
 #[derive(Debug)]
@@ -45,17 +49,45 @@ impl AtomicF32 {
     }
 }
 
-#[allow(dead_code)]
+/// Adds `inc` to `value`, rounding the result to `precision` decimals.
 pub fn inc_by_precision<T: Into<f64> + From<f64>>(value: T, inc: T, precision: u32) -> T {
     // Convert to f64, calculate, convert back to T
-    let base: f64 = 10.0;
-    let value_f64 = value.into();
-    let inc_f64 = inc.into();
-    let result = (value_f64 + inc_f64) * base.powf((precision - 1) as f64).round() / 10.0;
+    let scale = 10f64.powi(precision as i32);
+    let result = ((value.into() + inc.into()) * scale).round() / scale;
     T::from(result)
 }
 
-#[allow(dead_code)]
+/// Number of decimals needed to show a multiple of `step`.
+pub fn precision_of(step: f64) -> u32 {
+    (0..9)
+        .find(|&precision| {
+            let scaled = step * 10f64.powi(precision as i32);
+            (scaled - scaled.round()).abs() < 1e-9
+        })
+        .unwrap_or(9)
+}
+
 pub fn round_by_precision<T: Into<f64> + From<f64>>(value: T, precision: u32) -> T {
     inc_by_precision(value, 0.0.into(), precision)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inc_by_precision_rounds_the_sum() {
+        assert_eq!(inc_by_precision(120.1, 0.1, 1), 120.2);
+        assert_eq!(inc_by_precision(120.0, -0.1, 1), 119.9);
+        assert_eq!(inc_by_precision(99.96, 0.0, 1), 100.0);
+        assert_eq!(inc_by_precision(120.4, 5.0, 0), 125.0);
+    }
+
+    #[test]
+    fn precision_of_steps() {
+        assert_eq!(precision_of(5.0), 0);
+        assert_eq!(precision_of(1.0), 0);
+        assert_eq!(precision_of(0.1), 1);
+        assert_eq!(precision_of(0.25), 2);
+    }
+}